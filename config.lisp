@@ -11,21 +11,21 @@
   (load "sim/components.lisp"))
 
 ;; But the state of the simulator needs to be reset every time this
-;; file is being reloaded.
+;; file is being reloaded, before loading the defaults.  Each of the
+;; defaults in `sim/defaults.lisp', like the API service config, the
+;; consumer power and the component defaults, can be overridden below.
 (reset-state)
+(load "sim/defaults.lisp")
 
 
-;; Simulator configuration
-(setq state-update-interval-ms 200)
-
-
-;; API service config
-(setq socket-addr "[::1]:8800")  ;; Needs restart to take effect.
-(setq retain-requests-duration-ms 60000)
-(setq battery-interval 1000)
-(setq inverter-interval 1000)
-(setq meter-interval 200)
-(setq ev-charger-interval 1000)
+;; Failures injected into the API's unary calls, see
+;; `sim/defaults.lisp'.
+(setq rpc-faults
+      '((set-power-active . ((components . (1002))
+                             (error-rate . 0.0)
+                             (status . unavailable)
+                             (delay-ms . 0.0)
+                             (ignore-rate . 0.0)))))
 
 
 ;; Microgrid config
@@ -33,64 +33,50 @@
                  (location . (52.52 13.405))))  ;; Berlin
 
 
-;; Simulation config
-
-;; This simulates the external factors that affect the microgrid,
-;; including the consumer power and the state of the grid.
-(every
- :milliseconds 200
- :call (lambda ()
-         (setq consumer-power
-               (+ 48000 (random 100)))
-
-         (setq voltage-per-phase
-               (list (+ 229.0 (/ (random 200) 100.0))
-                     (+ 229.0 (/ (random 200) 100.0))
-                     (+ 229.0 (/ (random 200) 100.0))))
-
-         (setq power-factor-per-phase
-               (list (+ 0.88 (/ (random 5) 100.0))
-                     (+ 0.88 (/ (random 5) 100.0))
-                     (+ 0.88 (/ (random 5) 100.0))))
-
-         (setq ac-frequency
-               (+ 49.99 (/ (random 4) 100.0)))))
-
-;; Component defaults.  All these defaults can be overridden
-;; separately for individual components, if necessary.
-(setq battery-defaults '((initial-soc      . 90.0)
-                         (soc-lower        . 10.0)
-                         (soc-upper        . 90.0)
-                         (capacity         . 92000.0)
-                         (voltage          . 800.0)
-                         (rated-bounds     . (-30000.0 30000.0))
-                         (exclusion-bounds . (0.0 0.0))
-                         (component-state  . idle)
-                         (relay-state      . closed)))
-
-(setq meter-defaults '((component-state . ok)))
-
-(setq battery-inverter-defaults `((component-state . idle)
-                                  (rated-bounds    . (-30000.0 30000.0))))
-
-(setq solar-inverter-defaults `((component-state . idle)
-                                (rated-bounds    . (-30000.0 0.0))))
-
-(setq ev-charger-defaults
-      (let* ((max-current-per-phase 16.0)
-             (max-power-per-phase (seq-map
-                                   (lambda (x) (* max-current-per-phase x))
-                                   voltage-per-phase))
-             (max-power (seq-reduce #'+ max-power-per-phase 0.0)))
-        `((initial-soc     . 50.0)
-          (soc-lower       . 0.0)
-          (soc-upper       . 100.0)
-          (component-state . ready)
-          (cable-state     . ev-locked)
-          (rated-bounds    . (0.0 ,max-power))
-          (capacity        . 30000.0)
-          (inclusion-lower . 0.0)
-          (inclusion-upper . ,max-power))))
+;; Scripted grid conditions.  `grid-frequency' and
+;; `grid-voltage-per-phase' in `sim/defaults.lisp' apply these events on top of the
+;; random values.  Each event ramps to its target over `:ramp' seconds,
+;; starting `:after' seconds of simulated time after the simulation
+;; started, holds it for `:duration' seconds, and ramps back.  Events
+;; in scenario actions start `:after' seconds after the action.  Voltages are given as a factor
+;; of the nominal voltage, optionally for a single `:phase'.
+;;
+;; (grid-event :frequency 49.8 :after 30 :ramp 5 :duration 60)
+;; (grid-event :voltage 0.9 :phase 2 :after 120 :duration 10)
+
+;; Grid fuse.  The fuse trips after being overloaded for a while,
+;; disconnecting the site, and has to be reset with
+;; `(reset-grid-fuse)'.  Each entry of the trip curve is a multiple of
+;; the rated current, and the number of seconds the fuse holds at that
+;; load.  Set the curve to nil to only log overloads.  Overloads, trips
+;; and resets are kept in `grid-fuse-events', with the seconds of
+;; simulated time they happened at, and reported as `grid-fuse' events
+;; on the admin API's `/events' endpoint.
+(setq grid-fuse-trip-curve '((1.1 . 3600.0)
+                             (1.5 . 120.0)
+                             (2.0 . 20.0)
+                             (3.0 . 2.0)
+                             (5.0 . 0.1)))
+(setq grid-fuse-cool-down-ms 60000.0)
+
+;; `measurement' adds sensor errors to the reported AC values of
+;; meters, inverters and ev-chargers.  It takes an optional `seed', for
+;; reproducible errors, and an alist per metric (`power', `current',
+;; `voltage' or `frequency'), with any of `noise', `offset',
+;; `accuracy-class' (%), `quantization', `outlier-rate' and
+;; `outlier-factor'.  Without it, the exact values are reported.  To
+;; add errors to all meters, override their defaults like this:
+;;
+;; (setq meter-defaults '((component-state . ok)
+;;                        (measurement
+;;                         . ((seed    . 42)
+;;                            (power   . ((accuracy-class . 1.0)
+;;                                        (noise          . 20.0)
+;;                                        (quantization   . 1.0)))
+;;                            (current . ((accuracy-class . 1.0)
+;;                                        (quantization   . 0.01)))
+;;                            (voltage . ((noise          . 0.5)
+;;                                        (quantization   . 0.1)))))))
 
 
 ;; And finally, this builds the component graph/config of the
@@ -107,6 +93,7 @@
                             (make-meter
                              :successors (list
                                           (make-battery-inverter
+                                           :config '((grid-forming . t))
                                            :successors (list
                                                         (make-battery)))))
 
@@ -114,22 +101,45 @@
                             (make-meter
                              :successors (list
                                           (make-battery-inverter
+                                           :config '((fcr-power . 10000.0))
                                            :successors (list
                                                         (make-battery)))))
 
                             ;; ev chargers
+                            ;;
+                            ;; `:comm-faults' impairs a component's data
+                            ;; stream, with these optional keys:
+                            ;; `drop-rate', `delay-ms', `jitter-ms',
+                            ;; `stale-rate', `disconnect-after-s' and
+                            ;; `disconnect-status'.  For a lossy stream
+                            ;; from this meter, add:
+                            ;;
+                            ;;   :comm-faults '((drop-rate . 0.05)
+                            ;;                  (delay-ms . 100.0)
+                            ;;                  (jitter-ms . 300.0))
                             (make-meter
                              :successors (list
-                                          (make-ev-charger)
+                                          (make-ev-charger :sessions t)
                                           (make-ev-charger
                                            :config '((initial-soc . 10.0)
-                                                     (cable-state . ev-locked)))))
+                                                     (cable-state . ev-locked)
+                                                     ;; single-phase, on L2
+                                                     (phases . (2))
+                                                     (max-current-per-phase . 32.0)))))
 
                             ;; solar inverters
+                            ;;
+                            ;; `:profile' can be `clear-sky', a profile
+                            ;; from `(load-daily-profile "pv.csv")', or
+                            ;; any expression returning the sunlight %.
+                            ;; `:sunlight%' scales the profile, and the
+                            ;; cloud options add noise on top of it.
                             (make-meter
                              :successors (list
                                           (make-solar-inverter
-                                           :sunlight% 100.0
+                                           :profile 'clear-sky
+                                           :cloud-cover 10.0
+                                           :cloud-variability 2.0
                                            :config '((component-state . idle)
                                                      (rated-bounds . (-8000.0 0.0))))
                                           (make-solar-inverter :sunlight% 60.0)))
@@ -0,0 +1,18 @@
+;; Checks that the site keeps running from the grid-forming battery
+;; through a grid outage, and that the grid meter reads zero meanwhile.
+
+(scenario "Grid outage"
+  (at 30
+      (grid-outage)
+      (expect-within 10 (equal (component-value 2 'power) 0.0)
+                     "grid meter reads zero during outage")
+      (expect-always 60 (not (null islanded))
+                     "site stays islanded"))
+  (at 90 (restore-grid))
+  (at 100 (expect-within 10 (grid-connected-p)
+                         "grid is reconnected"))
+  (at (minutes 2)
+      (fail-component 1004)
+      (expect-within 10 (eq (component-value 1004 'component-state) 'error)
+                     "battery 1004 reports an error"))
+  (at (minutes 3) (recover-component 1004)))
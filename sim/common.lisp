@@ -5,8 +5,61 @@
   (setq comp--id--counter 1000)
   (setq connections-alist nil)
   (setq components-alist nil)
+  (setq hidden-connections-alist nil)
+  (setq hidden-components-alist nil)
   (setq state-update-functions nil)
-  (setq metadata nil))
+  (setq battery-inverters-alist nil)
+  (setq grid-meter-ids nil)
+  ;; Grid events from the config are anchored at the simulation start,
+  ;; so they are recreated unchanged.
+  (setq grid-events nil)
+  (setq metadata nil)
+  (setq registry-changed t))
+
+;; Set whenever the components or their connections change, so that
+;; the simulator rebuilds its registry of components after evaluating
+;; code at runtime.  Changes to the request timeout settings or to
+;; `single-controller' at runtime have to set it too.
+(setq registry-changed t)
+
+;; Milliseconds of simulated time since the simulation started,
+;; advanced with each state update.
+(setq simulation-time-ms 0)
+
+;; The wall-clock time the simulation started at, in seconds since the
+;; unix epoch.  Daily profiles and `clear-sky%' follow the simulated
+;; time from there.  Configs can set it, to simulate a fixed date.
+(setq simulation-start-time (unix-time))
+
+;; The state of the grid connection is kept across reloads, because a
+;; tripped fuse stays tripped until it is reset with `reset-grid-fuse'.
+(setq grid-state 'connected)
+(setq islanded nil)
+(setq unserved-load 0.0)
+(setq grid-overloaded nil)
+(setq grid-fuse-heat 0.0)
+(setq grid-fuse-events nil)
+(setq grid-fuse-trip-curve nil)
+(setq grid-fuse-cool-down-ms 60000.0)
+
+;; Components failed with `fail-component' stay failed across reloads,
+;; until they are recovered with `recover-component'.
+(setq failed-components nil)
+
+;; Components switched to standby with `standby-component', like when
+;; their power request expires, until they get a new power request.
+(setq standby-components nil)
+
+;; Component states set at runtime, as an alist of component IDs and
+;; states, like for inverters that shut down while the site is
+;; islanded.  They override the state in the streamed data, without
+;; reloading the config.
+(setq component-state-overrides nil)
+
+;; Lisp code can't reload the config while it is running, so it sets
+;; this instead, and the simulator reloads the config after the current
+;; state update.
+(setq reload-requested nil)
 
 (defun get-comp-id ()
   (setq comp--id--counter (+ comp--id--counter 1)))
@@ -20,6 +73,14 @@
   (intern (format "component-energy-%s" id)))
 
 
+(defun energy-consumed-symbol-from-id (id)
+  (intern (format "component-energy-consumed-%s" id)))
+
+
+(defun energy-delivered-symbol-from-id (id)
+  (intern (format "component-energy-delivered-%s" id)))
+
+
 (defun soc-symbol-from-id (id)
   (intern (format "component-soc-%s" id)))
 
@@ -40,12 +101,46 @@
   (intern (format "component-set-power-func-%s" id)))
 
 
+(defun capacity-symbol-from-id (id)
+  (intern (format "component-capacity-%s" id)))
+
+
+(defun initial-soc-symbol-from-id (id)
+  (intern (format "component-initial-soc-%s" id)))
+
+
+(defun cable-state-symbol-from-id (id)
+  (intern (format "component-cable-state-%s" id)))
+
+
+(defun session-symbol-from-id (id)
+  (intern (format "component-session-%s" id)))
+
+
+(defun session-clock-symbol-from-id (id)
+  (intern (format "component-session-clock-%s" id)))
+
+
+(defun session-schedule-symbol-from-id (id)
+  (intern (format "component-session-schedule-%s" id)))
+
+
+(defun sunlight-symbol-from-id (id)
+  (intern (format "component-sunlight-%s" id)))
+
+
+(defun cloud-symbol-from-id (id)
+  (intern (format "component-cloud-%s" id)))
+
+
 (defun add-to-connections-alist (id-from id-to)
+  (setq registry-changed t)
   (setq connections-alist (cons (cons id-from id-to)
                                 connections-alist)))
 
 
 (defun add-to-components-alist (alist)
+  (setq registry-changed t)
   (setq components-alist (cons alist
                                components-alist)))
 
@@ -56,9 +151,22 @@
     (add-to-connections-alist id-from id-to)))
 
 
+(defun add-to-hidden-components-alist (alist)
+  (setq registry-changed t)
+  (setq hidden-components-alist (cons alist
+                                      hidden-components-alist)))
+
+
+;; Connections to hidden components are not exposed over the API, but
+;; are kept for exporting the topology.
 (defun connect-successors (id successors)
   (dolist (successor successors)
-    (unless (alist-get 'hidden successor)
+    (if (alist-get 'hidden successor)
+        (progn
+          (setq registry-changed t)
+          (setq hidden-connections-alist
+                (cons (cons id (alist-get 'id successor))
+                      hidden-connections-alist)))
       (add-to-connections-alist id (alist-get 'id successor)))))
 
 
@@ -85,6 +193,25 @@
                         (setq p2-expr (cons '+ p2-expr))
                         (setq p3-expr (cons '+ p3-expr))))))
 
+(defun make-per-phase-power-expr (successors)
+  ;; Sums up the per-phase power of the successors, so that unbalanced
+  ;; loads show up in the meters above them.  Successors that only
+  ;; report their total power are assumed to be balanced.
+  (let ((p1-expr ())
+        (p2-expr ())
+        (p3-expr ()))
+    (dolist (successor successors)
+      (when-let ((power (alist-get 'power successor)))
+        (let ((per-phase-power (or (alist-get 'per-phase-power successor)
+                                   `(calc-per-phase-power ,power))))
+          (setq p1-expr (cons `(car ,per-phase-power) p1-expr))
+          (setq p2-expr (cons `(cadr ,per-phase-power) p2-expr))
+          (setq p3-expr (cons `(caddr ,per-phase-power) p3-expr)))))
+    (when p1-expr (list 'list
+                        (cons '+ p1-expr)
+                        (cons '+ p2-expr)
+                        (cons '+ p3-expr)))))
+
 (defun make-battery-bounds-check-expr (successors)
   (let ((sum-incl-lower-expr ())
         (sum-incl-upper-expr ())
@@ -126,13 +253,200 @@
          (set-power-func (eval (set-power-func-symbol-from-id id)))
          (power (ftruncate power)))
 
-    (if (funcall bounds-check-func power)
-        (progn
-          (funcall set-power-func power)
-          nil)
-        (let ((err (format "Requested power %f is out of bounds for component id %d" power id)))
-          (log.warn err)
-          err))))
+    (cond
+      ((or (not (site-powered-p))
+           (and (not (grid-connected-p))
+                (battery-inverter-p id)))
+       (let ((err (format "Can't set power of component id %d: grid is %s" id grid-state)))
+         (log.warn err)
+         err))
+      ((funcall bounds-check-func power)
+       (funcall set-power-func power)
+       (when (contains-id standby-components id)
+         (setq standby-components
+               (seq-filter (lambda (x) (not (equal x id))) standby-components))
+         (setq reload-requested t))
+       nil)
+      (t
+       (let ((err (format "Requested power %f is out of bounds for component id %d" power id)))
+         (log.warn err)
+         err)))))
+
+
+(defun grid-connected-p ()
+  (eq grid-state 'connected))
+
+
+(defun site-powered-p ()
+  ;; The site has power while connected to the grid, or while
+  ;; grid-forming inverters keep it running as an island.
+  (or (grid-connected-p) islanded))
+
+
+(defun fcr-adjusted-power (power fcr-power deadband full-activation lower upper)
+  ;; Adds the FCR droop for the current grid frequency to `power',
+  ;; without leaving the rated bounds.  There is no droop while the
+  ;; grid is disconnected.
+  (if (grid-connected-p)
+      (max lower
+           (min upper
+                (+ power
+                   (fcr-droop ac-frequency fcr-power deadband full-activation))))
+    power))
+
+
+(defun contains-id (ids id)
+  (seq-filter (lambda (x) (equal x id)) ids))
+
+
+(defun battery-inverter-p (id)
+  (seq-filter (lambda (inv) (equal (car inv) id)) battery-inverters-alist))
+
+
+(defun grid-forming-batteries ()
+  (let ((batteries nil))
+    (dolist (inv battery-inverters-alist)
+      (when (alist-get 'grid-forming (cdr inv))
+        (setq batteries `(,@(alist-get 'batteries (cdr inv)) ,@batteries))))
+    batteries))
+
+
+(defun meter-reading (id value)
+  ;; Meters read zero when the site has no power.  Meters at the grid
+  ;; connection point also read zero while the grid is disconnected.
+  (if (or (not (site-powered-p))
+          (and (not (grid-connected-p))
+               (contains-id grid-meter-ids id)))
+      (if (consp value) '(0.0 0.0 0.0) 0.0)
+    value))
+
+
+(defun meter-data-exprs (id exprs)
+  (seq-map (lambda (entry)
+             (if (eq (car entry) 'voltage)
+                 entry
+               (cons (car entry) `(meter-reading ,id ,(cdr entry)))))
+           exprs))
+
+
+(defun make-energy-counter (id power-expr)
+  ;; Registers a state update function that integrates `power-expr'
+  ;; into the energy counters of component `id', in Wh.  Positive power
+  ;; is counted as consumed energy, and negative power as delivered
+  ;; energy.  Returns the data alist entries for the counters.
+  (let ((consumed-symbol (energy-consumed-symbol-from-id id))
+        (delivered-symbol (energy-delivered-symbol-from-id id)))
+    (unless (boundp consumed-symbol)
+      (set consumed-symbol 0.0)
+      (set delivered-symbol 0.0))
+    (when power-expr
+      (setq state-update-functions
+            (cons (eval (list 'lambda '(ms-since-last-call)
+                              `(let ((energy (* ,power-expr
+                                                (/ ms-since-last-call
+                                                   ,(* 60.0 60.0 1000.0)))))
+                                 (if (> energy 0.0)
+                                     (setq ,consumed-symbol
+                                           (+ ,consumed-symbol energy))
+                                   (setq ,delivered-symbol
+                                         (- ,delivered-symbol energy))))))
+                  state-update-functions)))
+    `((energy-consumed  . ,consumed-symbol)
+      (energy-delivered . ,delivered-symbol))))
+
+
+(defun component-config (id config defaults)
+  ;; Returns the config alist of component `id', with its
+  ;; `component-state' overridden if it has been failed, or switched to
+  ;; standby.
+  `(,@(cond ((contains-id failed-components id)
+             '((component-state . error)))
+            ((contains-id standby-components id)
+             '((component-state . standby))))
+    ,@config
+    ,@defaults))
+
+
+(defun fail-component (id)
+  (log.warn (format "Failing component %s." id))
+  (setq failed-components (cons id failed-components))
+  (setq reload-requested t)
+  nil)
+
+
+(defun recover-component (id)
+  (log.info (format "Recovering component %s." id))
+  (setq failed-components
+        (seq-filter (lambda (x) (not (equal x id))) failed-components))
+  (setq reload-requested t)
+  nil)
+
+
+(defun standby-component (id)
+  ;; Sets the power of component `id' to zero, and reports it in the
+  ;; standby state until its power is set again.
+  (let ((err (set-power-active id 0.0)))
+    (unless err
+      (log.info (format "Switching component %s to standby." id))
+      (unless (contains-id standby-components id)
+        (setq standby-components (cons id standby-components))
+        (setq reload-requested t)))
+    err))
+
+
+(defun request-timeout-fallback-for (id)
+  ;; Returns what happens to component `id' when its power request
+  ;; expires: `zero', `hold', `standby' or a power setpoint, from
+  ;; `request-timeout-fallbacks' or `request-timeout-fallback'.  Configs
+  ;; can redefine this, to decide it some other way.
+  (let ((entry (car (seq-filter (lambda (x) (equal (car x) id))
+                                (and (boundp 'request-timeout-fallbacks)
+                                     request-timeout-fallbacks)))))
+    (cond (entry (cdr entry))
+          ((boundp 'request-timeout-fallback) request-timeout-fallback)
+          (:else 'zero))))
+
+
+(defun component-value (id key)
+  ;; Returns the value of `key' in the data that is streamed for
+  ;; component `id'.
+  (let* ((comp (component-from-id id))
+         (data (funcall (alist-get 'data (alist-get 'stream comp)) id)))
+    (eval (alist-get key data))))
+
+
+(defun component-from-id (id)
+  (car (seq-filter (lambda (comp) (equal (alist-get 'id comp) id))
+                   components-alist)))
+
+
+(defun start-component (id)
+  (if (eq (alist-get 'category (component-from-id id)) 'grid)
+      (progn
+        (restore-grid)
+        nil)
+    (format "Starting component id %d is not supported" id)))
+
+
+(defun stop-component (id)
+  (if (eq (alist-get 'category (component-from-id id)) 'grid)
+      (progn
+        (grid-outage)
+        nil)
+    (format "Stopping component id %d is not supported" id)))
+
+
+(defun component-state-override (id)
+  (cdr (car (seq-filter (lambda (x) (equal (car x) id))
+                        component-state-overrides))))
+
+
+(defun set-component-state-override (id state)
+  ;; Reports component `id' in `state' until it is set to nil.
+  (let ((others (seq-filter (lambda (x) (not (equal (car x) id)))
+                            component-state-overrides)))
+    (setq component-state-overrides
+          (if state (cons (cons id state) others) others))))
 
 
 (defun component-data-maker (data-alist defaults-alist keys)
@@ -146,6 +460,13 @@
         (if-let ((val (alist-get key defaults-alist)))
             (setq args-alist (cons (cons key `(quote ,val)) args-alist)))))
 
+    (when-let ((state (alist-get 'component-state args-alist)))
+      (setq args-alist
+            (cons `(component-state
+                    . (or (component-state-override ,(alist-get 'id data-alist))
+                          ,state))
+                  args-alist)))
+
     (eval (list 'lambda '(_) `(quote ,args-alist)))))
 
 
@@ -183,6 +504,63 @@
     '(0.0 0.0 0.0)))
 
 
+(defun phase-value (values phase)
+  ;; Returns the value for `phase' (1, 2 or 3) from a list of
+  ;; per-phase values, or `values' itself if it is a single number.
+  (cond
+    ((not (consp values)) values)
+    ((equal phase 1) (car values))
+    ((equal phase 2) (cadr values))
+    (t (caddr values))))
+
+
+(defun phase-voltage-sum (phases)
+  (seq-reduce '+
+              (seq-map (lambda (phase) (phase-value voltage-per-phase phase))
+                       phases)
+              0.0))
+
+
+(defun has-phase (phases phase)
+  (seq-filter (lambda (p) (equal p phase)) phases))
+
+
+(defun ev-phase-currents (power phases)
+  ;; EVs draw the same current on each of the grid `phases' they are
+  ;; connected to, and none on the others.
+  (if (numberp power)
+      (let ((current (/ power (phase-voltage-sum phases))))
+        (list (if (has-phase phases 1) current 0.0)
+              (if (has-phase phases 2) current 0.0)
+              (if (has-phase phases 3) current 0.0)))
+    '(0.0 0.0 0.0)))
+
+
+(defun ev-phase-power (power phases)
+  (let ((currents (ev-phase-currents power phases)))
+    (list (* (car currents) (car voltage-per-phase))
+          (* (cadr currents) (cadr voltage-per-phase))
+          (* (caddr currents) (caddr voltage-per-phase)))))
+
+
+(defun ev-charger-min-power (phases min-currents)
+  ;; The current has to be above the minimum of every connected phase.
+  (* (seq-reduce 'max
+                 (seq-map (lambda (phase) (phase-value min-currents phase))
+                          phases)
+                 0.0)
+     (phase-voltage-sum phases)))
+
+
+(defun ev-charger-max-power (phases max-currents)
+  ;; The current can't exceed the limit of any connected phase.
+  (* (seq-reduce 'min
+                 (seq-map (lambda (phase) (phase-value max-currents phase))
+                          phases)
+                 1000000000.0)
+     (phase-voltage-sum phases)))
+
+
 (defun is-healthy-battery (bat)
   (let ((comp-state (alist-get 'component-state bat))
         (relay-state (alist-get 'relay-state bat)))
@@ -200,13 +578,15 @@
         (eq comp-state 'charging)
         (eq comp-state 'discharging))))
 
+(defun is-ready-ev-charger (ev)
+  (let ((comp-state (alist-get 'component-state ev)))
+    (or (eq comp-state 'ready)
+        (eq comp-state 'charging)
+        (eq comp-state 'discharging))))
+
 (defun is-healthy-ev-charger (ev)
-  (let ((comp-state (alist-get 'component-state ev))
-        (cable-state (alist-get 'cable-state ev)))
-    (and (or (eq comp-state 'ready)
-             (eq comp-state 'charging)
-             (eq comp-state 'discharging))
-         (eq cable-state 'ev-locked))))
+  (and (is-ready-ev-charger ev)
+       (eq (alist-get 'cable-state ev) 'ev-locked)))
 
 (defun power->component-state (power)
   (cond
@@ -222,6 +602,15 @@
     ((< power 0.0) 'discharging)
     (:else         'ready)))
 
+(defun random-between (range)
+  ;; Returns a random float within `range', which is a list of the
+  ;; lower and upper limits.  Numbers are returned as they are.
+  (if (consp range)
+      (+ (car range)
+         (* (- (cadr range) (car range))
+            (/ (random 10001) 10000.0)))
+    range))
+
 (defun bounded-exp-decay (start stop val base min_val)
   (let* ((base (max base 1.1))
          (factor (/ 10.0 (- stop start)))
@@ -234,6 +623,27 @@
       (t (+ shift (* (- 1.0 shift)
                      (expt base (- start val))))))))
 
+(defun profile-expr (profile)
+  ;; Returns an expression that evaluates to the current value of
+  ;; `profile', which can be the symbol `clear-sky', a profile loaded
+  ;; with `load-daily-profile' or `load-profile', or any lisp
+  ;; expression.
+  (cond
+    ((null profile) 100.0)
+    ((eq profile 'clear-sky) '(clear-sky%))
+    ((consp profile) profile)
+    (t `(profile-value (quote ,profile)))))
+
+(defun next-cloud-factor (factor cloud-cover variability)
+  ;; A mean-reverting random walk around the clear fraction of the
+  ;; sky, so that clouds pass by instead of flickering every tick.
+  (let* ((target (- 1.0 (/ cloud-cover 100.0)))
+         (noise (* (/ variability 100.0)
+                   (- (/ (random 2001) 1000.0) 1.0))))
+    (min 1.0 (max 0.0 (+ factor
+                         (* 0.1 (- target factor))
+                         noise)))))
+
 (defun repeat-every-impl (counter every-ms action ms-since-last-call)
   (let ((count (+ (eval counter) ms-since-last-call)))
     (set counter count)
@@ -15,9 +15,10 @@
   (let* ((id (or (plist-get plist :id) (get-comp-id)))
 
          (interval (or (plist-get plist :interval) battery-interval))
+         (comm-faults (plist-get plist :comm-faults))
 
          (config  (plist-get plist :config))
-         (config-alist `(,@config ,@battery-defaults))
+         (config-alist (component-config id config battery-defaults))
 
          (power-symbol  (power-symbol-from-id  id))
          (energy-symbol (energy-symbol-from-id id))
@@ -87,6 +88,7 @@
             (is-healthy . ,is-healthy)
             (stream   . ,(list
                           `(interval . ,interval)
+                          `(comm-faults . ,comm-faults)
                           (cons 'data
                                 (macroexpand '(battery-data-maker
                                         `((id    . ,id)
@@ -132,14 +134,16 @@
   (component-data-maker data-alist
                         defaults-alist
                         '(id power current voltage component-state
-                          per-phase-power inclusion-lower inclusion-upper)))
+                          per-phase-power inclusion-lower inclusion-upper
+                          energy-consumed energy-delivered)))
 
 (defun make-battery-inverter (&rest plist)
   (let* ((id (or (plist-get plist :id) (get-comp-id)))
          (interval (or (plist-get plist :interval) inverter-interval))
+         (comm-faults (plist-get plist :comm-faults))
 
          (config (plist-get plist :config))
-         (config-alist `(,@config ,@battery-inverter-defaults))
+         (config-alist (component-config id config battery-inverter-defaults))
 
          (successors (plist-get plist :successors))
 
@@ -150,19 +154,39 @@
 
          (is-healthy (is-healthy-inverter config-alist))
 
+         (healthy-batteries (seq-filter
+                             (lambda (b) (alist-get 'is-healthy b))
+                             successors))
+
+         ;; Inverters with an FCR power respond to frequency deviations
+         ;; on top of the power of their batteries, within their rated
+         ;; bounds.
+         (fcr-power (alist-get 'fcr-power config-alist))
+         (batteries-power-expr (make-power-expr successors))
+         (inverter-power-expr (if (and fcr-power batteries-power-expr)
+                                  `(fcr-adjusted-power ,batteries-power-expr
+                                                       ,fcr-power
+                                                       ,(alist-get 'fcr-deadband config-alist)
+                                                       ,(alist-get 'fcr-full-activation config-alist)
+                                                       ,rated-lower
+                                                       ,rated-upper)
+                                batteries-power-expr))
+
          (power-expr (when is-healthy
-                       `((power . ,(make-power-expr successors))
-                         (per-phase-power . (calc-per-phase-power ,(make-power-expr successors)))
+                       `((power . ,inverter-power-expr)
+                         (per-phase-power . (calc-per-phase-power ,inverter-power-expr))
                          (voltage . voltage-per-phase)
                          (current . (calc-per-phase-current
-                                     ,(make-power-expr successors)))
+                                     ,inverter-power-expr))
                          (component-state . (power->component-state
-                                             ,(make-power-expr successors))))))
+                                             ,inverter-power-expr)))))
          (bounds-expr `((inclusion-lower . ,rated-lower)
                         (inclusion-upper . ,rated-upper)))
          (bounds-check-func-symbol (bounds-check-func-symbol-from-id id))
          (set-power-func-symbol (set-power-func-symbol-from-id id))
 
+         (energy-expr (make-energy-counter id (alist-get 'power power-expr)))
+
          (inverter
           `((category . inverter)
             (type     . battery)
@@ -171,11 +195,14 @@
             ,@power-expr
             (stream   . ,(list
                           `(interval . ,interval)
+                          `(comm-faults . ,comm-faults)
+                          `(measurement . ,(alist-get 'measurement config-alist))
                           (cons 'data
                                 (macroexpand '(inverter-data-maker
                                         `((id . ,id)
                                           ,@bounds-expr
-                                          ,@power-expr)
+                                          ,@power-expr
+                                          ,@energy-expr)
                                         config-alist))))))))
 
     (log.trace (format "Adding battery inverter %s. Healthy: %s" id is-healthy))
@@ -193,10 +220,7 @@
                          nil))))
 
     (set set-power-func-symbol
-         (let* ((healthy-batteries (seq-filter
-                                    (lambda (b) (alist-get 'is-healthy b))
-                                    successors))
-                (num-batteries (length healthy-batteries))
+         (let* ((num-batteries (length healthy-batteries))
                 (expr ()))
            (dolist (battery healthy-batteries)
              (setq expr
@@ -220,6 +244,35 @@
                   nil))
            ))
 
+    ;; The batteries deliver the FCR power too, so it counts towards
+    ;; their energy, and with that their SoC.
+    (when (and is-healthy fcr-power healthy-batteries)
+      (setq state-update-functions
+            (cons (eval (list 'lambda '(ms-since-last-call)
+                              `(let ((fcr-share (/ (- ,inverter-power-expr
+                                                      ,batteries-power-expr)
+                                                   ,(length healthy-batteries))))
+                                 ,@(seq-map
+                                    (lambda (b)
+                                      (let ((energy-symbol (energy-symbol-from-id
+                                                            (alist-get 'id b))))
+                                        `(setq ,energy-symbol
+                                               (+ ,energy-symbol
+                                                  (* fcr-share
+                                                     (/ ms-since-last-call
+                                                        ,(* 60.0 60.0 1000.0)))))))
+                                    healthy-batteries))))
+                  state-update-functions)))
+
+    ;; Grid-forming inverters keep the site running when the grid is
+    ;; disconnected, the others shut down.
+    (setq battery-inverters-alist
+          (cons `(,id . ((grid-forming . ,(alist-get 'grid-forming config-alist))
+                         (batteries . ,(seq-map
+                                        (lambda (b) (alist-get 'id b))
+                                        healthy-batteries))))
+                battery-inverters-alist))
+
     (add-to-components-alist inverter)
     (connect-successors id successors)
     inverter))
@@ -227,19 +280,39 @@
 (defun make-solar-inverter (&rest plist)
   (let* ((id (or (plist-get plist :id) (get-comp-id)))
          (interval (or (plist-get plist :interval) inverter-interval))
+         (comm-faults (plist-get plist :comm-faults))
 
-         (sunlight% (plist-get plist :sunlight%))
+         (sunlight% (or (plist-get plist :sunlight%) 100.0))
+         (profile (plist-get plist :profile))
+         (cloud-cover (or (plist-get plist :cloud-cover) 0.0))
+         (cloud-variability (or (plist-get plist :cloud-variability) 0.0))
 
          (config (plist-get plist :config))
-         (config-alist `(,@config ,@solar-inverter-defaults))
+         (config-alist (component-config id config solar-inverter-defaults))
 
          (power-symbol  (power-symbol-from-id id))
          (min-power-symbol (power-symbol-from-id (format "min-%s" id)))
+         (sunlight-symbol (sunlight-symbol-from-id id))
+         (cloud-symbol (cloud-symbol-from-id id))
 
          (rated-bounds (or (alist-get 'rated-bounds config-alist) '(0.0 0.0)))
          (rated-lower (car rated-bounds))
          (rated-upper (cadr rated-bounds))
 
+         (sunlight-expr `(setq ,sunlight-symbol
+                               (* ,(profile-expr profile)
+                                  ,(/ sunlight% 100.0)
+                                  ,cloud-symbol)))
+         (cloud-expr `(setq ,cloud-symbol
+                            (next-cloud-factor ,cloud-symbol
+                                               ,cloud-cover
+                                               ,cloud-variability)))
+         (available-power-expr `(if (site-powered-p)
+                                    (* ,rated-lower (/ ,sunlight-symbol 100.0))
+                                  0.0))
+         (update-power-expr `(setq ,power-symbol
+                                   (max ,min-power-symbol ,available-power-expr)))
+
          (is-healthy (is-healthy-inverter config-alist))
 
          (power-expr (when is-healthy
@@ -254,6 +327,8 @@
          (bounds-check-func-symbol (bounds-check-func-symbol-from-id id))
          (set-power-func-symbol (set-power-func-symbol-from-id id))
 
+         (energy-expr (make-energy-counter id (alist-get 'power power-expr)))
+
          (inverter
           `((category . inverter)
             (type     . solar)
@@ -262,12 +337,15 @@
             ,@power-expr
             (stream   . ,(list
                           `(interval . ,interval)
+                          `(comm-faults . ,comm-faults)
+                          `(measurement . ,(alist-get 'measurement config-alist))
                           (cons 'data
                                 (macroexpand '(inverter-data-maker
                                         `((id . ,id)
                                           (inclusion-lower . ,rated-lower)
                                           (inclusion-upper . ,rated-upper)
-                                          ,@power-expr)
+                                          ,@power-expr
+                                          ,@energy-expr)
                                         config-alist))))))))
 
     (log.trace (format "Adding solar inverter %s. Healthy: %s" id is-healthy))
@@ -275,7 +353,20 @@
     (when (not (boundp min-power-symbol))
       (set min-power-symbol rated-lower))
 
-    (set power-symbol (max (eval min-power-symbol) (* rated-lower (/ sunlight% 100.0))))
+    (when (not (boundp cloud-symbol))
+      (set cloud-symbol (- 1.0 (/ cloud-cover 100.0))))
+
+    (eval sunlight-expr)
+    (eval update-power-expr)
+
+    ;; The available power follows the sunlight profile, so it has to
+    ;; be recalculated on every tick.
+    (setq state-update-functions
+          (cons (eval (list 'lambda '(ms-since-last-call)
+                            cloud-expr
+                            sunlight-expr
+                            update-power-expr))
+                state-update-functions))
 
     (set bounds-check-func-symbol
          (if is-healthy
@@ -288,14 +379,14 @@
     (set set-power-func-symbol
          (if is-healthy
              `(lambda (power)
-                (let ((min-power ,(* rated-lower (/ sunlight% 100.0))))
-                  (setq ,min-power-symbol (max power min-power))
-                  (if (< power min-power)
+                (let ((available-power ,available-power-expr))
+                  (setq ,min-power-symbol power)
+                  (if (< power available-power)
                       (progn
                         (log.info
                          (format "Given power %s W is not available for inverter %s.  Limiting to %s W."
-                                 power ,id min-power))
-                        (setq ,power-symbol min-power))
+                                 power ,id available-power))
+                        (setq ,power-symbol available-power))
                       (log.info (format "Setting power of inverter %s to %s W (was: %s W)"
                                         ,id
                                         power
@@ -316,17 +407,19 @@
 (defmacro meter-data-maker (data-alist defaults-alist)
   (component-data-maker data-alist
                         defaults-alist
-                        '(id power per-phase-power current voltage component-state)))
+                        '(id power per-phase-power current voltage component-state
+                          energy-consumed energy-delivered)))
 
 
 
 (defun make-meter (&rest plist)
   (let* ((id (or (plist-get plist :id) (get-comp-id)))
          (interval (or (plist-get plist :interval) meter-interval))
+         (comm-faults (plist-get plist :comm-faults))
          (power (plist-get plist :power))
 
          (config (plist-get plist :config))
-         (config-alist `(,@config ,@meter-defaults))
+         (config-alist (component-config id config meter-defaults))
 
          (successors (plist-get plist :successors))
          (hidden (plist-get plist :hidden))
@@ -341,8 +434,20 @@
                        (if-let ((power (or power
                                            (make-power-expr successors))))
                            `((power . ,power)
-                             (per-phase-power . (calc-per-phase-power ,power))
+                             (per-phase-power
+                              . ,(if (plist-get plist :power)
+                                     `(calc-per-phase-power ,power)
+                                   (make-per-phase-power-expr successors)))
                              (voltage . voltage-per-phase)))))
+         ;; The streamed data reflects the state of the grid
+         ;; connection, while the expressions in the meter's alist keep
+         ;; the actual power flows, for the meters above it.
+         (state-expr `((component-state
+                        . (if (site-powered-p)
+                              (quote ,(alist-get 'component-state config-alist))
+                            'error))))
+         (energy-expr (make-energy-counter id (alist-get 'power power-expr)))
+
          (meter
           `((category . meter)
             (name     . ,(format "meter-%s" id))
@@ -352,18 +457,24 @@
             ,@power-expr
             (stream   . ,(list
                           `(interval . ,interval)
+                          `(comm-faults . ,comm-faults)
+                          `(measurement . ,(alist-get 'measurement config-alist))
                           (cons 'data
                                 (macroexpand '(meter-data-maker
                                                `((id    . ,id)
-                                                 ,@current-expr
-                                                 ,@power-expr)
+                                                 ,@state-expr
+                                                 ,@(meter-data-exprs id current-expr)
+                                                 ,@(meter-data-exprs id power-expr)
+                                                 ,@energy-expr)
                                                config-alist))))))))
 
     (log.trace (format "Adding meter %s" id))
 
-    (unless hidden
-      (add-to-components-alist meter)
-      (connect-successors id successors))
+    (if hidden
+        (add-to-hidden-components-alist meter)
+      (progn
+        (add-to-components-alist meter)
+        (connect-successors id successors)))
     meter))
 
 
@@ -375,32 +486,137 @@
   (component-data-maker data-alist
                         defaults-alist
                         '(id power current voltage component-state
-                          cable-state inclusion-lower inclusion-upper)))
+                          per-phase-power cable-state
+                          inclusion-lower inclusion-upper)))
+
+(defun ev-session-make-ev (sessions ev clock)
+  ;; Fills in the parameters missing from the scheduled `ev' with
+  ;; random values from the ranges in `sessions'.
+  `((capacity    . ,(or (alist-get 'capacity ev)
+                        (random-between (alist-get 'ev-capacity sessions))))
+    (initial-soc . ,(or (alist-get 'initial-soc ev)
+                        (random-between (alist-get 'ev-initial-soc sessions))))
+    (max-power   . ,(or (alist-get 'max-power ev)
+                        (random-between (alist-get 'ev-max-power sessions))))
+    (depart-at   . ,(if-let ((depart (alist-get 'depart ev)))
+                        (* 1000.0 depart)
+                      (+ clock (* 60000.0 (random-between
+                                           (alist-get 'stay-minutes sessions))))))))
+
+(defun ev-session-arrival (id sessions clock ms-since-last-call)
+  ;; Returns the EV arriving at ev-charger `id' in this tick, if any.
+  ;; Scheduled arrivals are used when there is a schedule, otherwise
+  ;; EVs arrive randomly, at the configured average rate.
+  (let ((schedule-symbol (session-schedule-symbol-from-id id)))
+    (if (alist-get 'schedule sessions)
+        (let ((next (car (eval schedule-symbol))))
+          (when (and next (<= (* 1000.0 (alist-get 'arrive next)) clock))
+            (set schedule-symbol (cdr (eval schedule-symbol)))
+            (ev-session-make-ev sessions next clock)))
+      (let ((probability (* (alist-get 'arrivals-per-hour sessions)
+                            (/ ms-since-last-call 3600000.0))))
+        (when (< (random 1000000) (* probability 1000000.0))
+          (ev-session-make-ev sessions nil clock))))))
+
+(defun ev-session-update (id sessions ms-since-last-call)
+  ;; Moves ev-charger `id' through the session states:
+  ;;   unplugged -> ev-plugged -> ev-locked -> ev-plugged -> unplugged
+  (let* ((clock-symbol (session-clock-symbol-from-id id))
+         (session-symbol (session-symbol-from-id id))
+         (cable-state-symbol (cable-state-symbol-from-id id))
+         (clock (+ (eval clock-symbol) ms-since-last-call))
+         (session (eval session-symbol))
+         (cable-state (eval cable-state-symbol)))
+    (set clock-symbol clock)
+    (cond
+      ((eq cable-state 'unplugged)
+       (when-let ((ev (ev-session-arrival id sessions clock ms-since-last-call)))
+         (log.info (format "EV arrived at ev-charger %s: capacity %s Wh, SoC %s, max power %s W."
+                           id
+                           (alist-get 'capacity ev)
+                           (alist-get 'initial-soc ev)
+                           (alist-get 'max-power ev)))
+         (set (capacity-symbol-from-id id) (alist-get 'capacity ev))
+         (set (initial-soc-symbol-from-id id) (alist-get 'initial-soc ev))
+         (set (soc-symbol-from-id id) (alist-get 'initial-soc ev))
+         (set (energy-symbol-from-id id) 0.0)
+         (set cable-state-symbol 'ev-plugged)
+         (set session-symbol
+              `((transition-at . ,(+ clock (alist-get 'lock-delay-ms sessions)))
+                ,@ev))))
+      ((eq cable-state 'ev-plugged)
+       (when (>= clock (alist-get 'transition-at session))
+         (if (alist-get 'departing session)
+             (progn
+               (log.info (format "EV left ev-charger %s." id))
+               (set cable-state-symbol 'unplugged)
+               (set session-symbol nil))
+           (set cable-state-symbol 'ev-locked))))
+      ((eq cable-state 'ev-locked)
+       (when (>= clock (alist-get 'depart-at session))
+         (log.info (format "EV leaving ev-charger %s with SoC %s."
+                           id
+                           (eval (soc-symbol-from-id id))))
+         (set (power-symbol-from-id id) 0.0)
+         (set cable-state-symbol 'ev-plugged)
+         (set session-symbol
+              `((departing . t)
+                (transition-at . ,(+ clock (alist-get 'unplug-delay-ms sessions)))
+                ,@session)))))))
+
+(defun ev-session-limit (id power-limit)
+  ;; Limits the charging power to what the connected EV accepts.
+  (if (eq (eval (cable-state-symbol-from-id id)) 'ev-locked)
+      (min power-limit
+           (alist-get 'max-power (eval (session-symbol-from-id id))))
+    0.0))
 
 (defun make-ev-charger (&rest plist)
   (let* ((id (or (plist-get plist :id) (get-comp-id)))
          (interval (or (plist-get plist :interval) ev-charger-interval))
+         (comm-faults (plist-get plist :comm-faults))
          (config (plist-get plist :config))
-         (config-alist `(,@config ,@ev-charger-defaults))
+         (config-alist (component-config id config ev-charger-defaults))
+
+         ;; EVs arrive and depart by themselves when `:sessions' is
+         ;; given, either `t' or an alist overriding
+         ;; `ev-session-defaults'.
+         (sessions (when-let ((sessions (plist-get plist :sessions)))
+                     `(,@(when (consp sessions) sessions)
+                       ,@ev-session-defaults)))
 
          (power-symbol  (power-symbol-from-id id))
          (energy-symbol (energy-symbol-from-id id))
+         (capacity-symbol (capacity-symbol-from-id id))
+         (initial-soc-symbol (initial-soc-symbol-from-id id))
+         (cable-state-symbol (cable-state-symbol-from-id id))
 
          (capacity    (alist-get 'capacity    config-alist))
          (initial-soc (alist-get 'initial-soc config-alist))
 
          (soc-symbol (soc-symbol-from-id id))
          (soc-expr `(setq ,soc-symbol
-                          (+ ,initial-soc
+                          (+ ,initial-soc-symbol
                              ;; limit to 1 decimal place
                              (/ (fround
-                                 (* 1000.0 (/ ,energy-symbol ,capacity)))
+                                 (* 1000.0 (/ ,energy-symbol ,capacity-symbol)))
                                 10.0))))
 
 
-         (rated-bounds (or (alist-get 'rated-bounds config-alist) '(0.0 0.0)))
-         (rated-lower (car rated-bounds))
-         (rated-upper (cadr rated-bounds))
+         ;; The grid phases the ev-charger is connected to, e.g. `(2)'
+         ;; for a single-phase charger on L2.  Its currents and
+         ;; per-phase power are reported on these grid phases.
+         (phases (or (alist-get 'phases config-alist) '(1 2 3)))
+         (min-currents (or (alist-get 'min-current-per-phase config-alist) 6.0))
+         (max-currents (or (alist-get 'max-current-per-phase config-alist) 16.0))
+
+         ;; Without explicit rated bounds, the upper bound follows the
+         ;; current limits of the connected phases.
+         (rated-bounds (alist-get 'rated-bounds config-alist))
+         (rated-lower (or (car rated-bounds) 0.0))
+         (rated-upper (or (cadr rated-bounds)
+                          `(ev-charger-max-power (quote ,phases)
+                                                 (quote ,max-currents))))
 
          (incl-lower-symbol (inclusion-lower-symbol-from-id id))
          (incl-upper-symbol (inclusion-upper-symbol-from-id id))
@@ -410,24 +626,35 @@
 
          (incl-lower 0.0)
          (incl-upper-expr `(setq ,incl-upper-symbol
-                                 (if (< (- ,soc-upper ,soc-symbol) 10.0)
-                                     (* ,rated-upper
-                                        (bounded-exp-decay ,(- soc-upper 10.0)
-                                                           ,soc-upper
-                                                           ,soc-symbol
-                                                           1.2
-                                                           0.3))
-                                     ,rated-upper)))
-
-         (is-healthy (is-healthy-ev-charger config-alist))
+                                 ,(let ((soc-limit
+                                         `(if (< (- ,soc-upper ,soc-symbol) 10.0)
+                                              (* ,rated-upper
+                                                 (bounded-exp-decay ,(- soc-upper 10.0)
+                                                                    ,soc-upper
+                                                                    ,soc-symbol
+                                                                    1.2
+                                                                    0.3))
+                                            ,rated-upper)))
+                                    (if sessions
+                                        `(ev-session-limit ,id ,soc-limit)
+                                      soc-limit))))
+
+         (is-healthy (if sessions
+                         (is-ready-ev-charger config-alist)
+                       (is-healthy-ev-charger config-alist)))
 
          (power-expr (when is-healthy
                        `((power . ,power-symbol)
-                         (current . (ac-current-from-power ,power-symbol))
+                         (per-phase-power . (ev-phase-power ,power-symbol
+                                                            (quote ,phases)))
+                         (voltage . voltage-per-phase)
+                         (current . (ev-phase-currents ,power-symbol
+                                                       (quote ,phases)))
                          (component-state . (power->ev-component-state ,power-symbol)))))
 
          (bounds-expr `((inclusion-lower . 0.0)
-                        (inclusion-upper . ,rated-upper)))
+                        (inclusion-upper . ,(if sessions incl-upper-symbol rated-upper))
+                        (cable-state . ,cable-state-symbol)))
          (bounds-check-func-symbol (bounds-check-func-symbol-from-id id))
          (set-power-func-symbol (set-power-func-symbol-from-id id))
 
@@ -438,6 +665,8 @@
             ,@power-expr
             (stream   . ,(list
                           `(interval . ,interval)
+                          `(comm-faults . ,comm-faults)
+                          `(measurement . ,(alist-get 'measurement config-alist))
                           (cons 'data
                                 (macroexpand '(ev-charger-data-maker
                                                `((id . ,id)
@@ -452,11 +681,29 @@
       (set energy-symbol 0.0)
       (set soc-symbol (eval initial-soc)))
 
+    ;; The EV, and with it the capacity and the cable state, changes
+    ;; with every session, so they are taken from the config only for
+    ;; ev-chargers without sessions.
+    (if sessions
+        (when (not (boundp cable-state-symbol))
+          (set capacity-symbol capacity)
+          (set initial-soc-symbol (eval initial-soc))
+          (set cable-state-symbol 'unplugged)
+          (set (session-symbol-from-id id) nil)
+          (set (session-clock-symbol-from-id id) 0)
+          (set (session-schedule-symbol-from-id id)
+               (alist-get 'schedule sessions)))
+      (set capacity-symbol capacity)
+      (set initial-soc-symbol (eval initial-soc))
+      (set cable-state-symbol (alist-get 'cable-state config-alist)))
+
     (eval incl-upper-expr)
     (add-to-components-alist ev-charger)
 
     (setq state-update-functions
           (cons (list 'lambda '(ms-since-last-call)
+                      (when sessions
+                        `(ev-session-update ,id (quote ,sessions) ms-since-last-call))
                       `(eval (setq ,energy-symbol
                                    (+ ,energy-symbol ;; ->> ?
                                       (* ,power-symbol
@@ -483,17 +730,23 @@
     (set set-power-func-symbol
          (if is-healthy
              `(lambda (power)
-                (if (< power ,(* 6.0 3 220.0))
-                    (progn
-                      (log.info
-                       (format "Given power %s W is too low for ev-charger %s.  Not charging."
-                               power ,id))
-                      (setq ,power-symbol 0.0))
-                    (log.info (format "Setting power of ev-charger %s to %s W (was: %s W)"
-                                      ,id
-                                      power
-                                      ,(power-symbol-from-id id)))
-                    (setq ,(power-symbol-from-id id) power)))
+                (cond
+                  ((not (eq ,cable-state-symbol 'ev-locked))
+                   (log.info
+                    (format "No EV locked to ev-charger %s.  Not charging." ,id))
+                   (setq ,power-symbol 0.0))
+                  ((< power (ev-charger-min-power (quote ,phases)
+                                                  (quote ,min-currents)))
+                   (log.info
+                    (format "Given power %s W is too low for ev-charger %s.  Not charging."
+                            power ,id))
+                   (setq ,power-symbol 0.0))
+                  (t
+                   (log.info (format "Setting power of ev-charger %s to %s W (was: %s W)"
+                                     ,id
+                                     power
+                                     ,(power-symbol-from-id id)))
+                   (setq ,(power-symbol-from-id id) power))))
            '(lambda (power)
              (log.error "Can't set power: ev-charger is unhealthy")
              nil)))
@@ -520,18 +773,171 @@
 ;; Grid ;;
 ;;;;;;;;;;
 
+(defun grid-fuse-add-event (event currents)
+  (push-event 'grid-fuse nil `((event    . ,event)
+                               (currents . ,currents)))
+  ;; Stamped with the simulated time in seconds, like grid events, so
+  ;; that they line up with the trip timing when time is stepped.
+  (setq grid-fuse-events
+        (cons `((time     . ,(/ simulation-time-ms 1000.0))
+                (event    . ,event)
+                (currents . ,currents))
+              grid-fuse-events)))
+
+(defun grid-fuse-trip-time (ratio)
+  ;; Returns the time in seconds the fuse holds at `ratio' times its
+  ;; rated current, from `grid-fuse-trip-curve', or nil if it doesn't
+  ;; trip at all.
+  (let ((trip-time nil))
+    (dolist (point grid-fuse-trip-curve)
+      (when (>= ratio (car point))
+        (setq trip-time (cdr point))))
+    trip-time))
+
+(defun disconnect-grid (state)
+  ;; Cuts the site off from the grid.  Grid-forming inverters take
+  ;; over if there are any, otherwise the whole site loses power.
+  (setq grid-state state)
+  (setq grid-overloaded nil)
+  (setq grid-fuse-heat 0.0)
+  (setq unserved-load 0.0)
+  (setq islanded (and (grid-forming-batteries) t))
+  ;; The other battery inverters stand by until the grid is back.
+  (dolist (inv battery-inverters-alist)
+    (unless (alist-get 'grid-forming (cdr inv))
+      (set-component-state-override (car inv) 'standby)))
+  (dolist (comp components-alist)
+    (let ((category (alist-get 'category comp)))
+      (when (or (eq category 'battery)
+                (and (eq category 'ev-charger) (not islanded)))
+        (set (power-symbol-from-id (alist-get 'id comp)) 0.0))))
+  (if islanded
+      (log.warn (format "Grid is %s.  Site is islanded." state))
+    (log.error (format "Grid is %s.  Site has no power." state))))
+
+(defun reconnect-grid ()
+  (log.info "Grid is connected.")
+  (setq grid-state 'connected)
+  (setq islanded nil)
+  (setq unserved-load 0.0)
+  (dolist (inv battery-inverters-alist)
+    (unless (alist-get 'grid-forming (cdr inv))
+      (set-component-state-override (car inv) nil)))
+  (dolist (id (grid-forming-batteries))
+    (set (power-symbol-from-id id) 0.0)))
+
+(defun grid-outage ()
+  (when (grid-connected-p)
+    (disconnect-grid 'outage)))
+
+(defun restore-grid ()
+  (when (eq grid-state 'outage)
+    (reconnect-grid)))
+
+(defun grid-fuse-trip (currents)
+  (log.error (format "Grid fuse tripped at %s A." currents))
+  (grid-fuse-add-event 'trip currents)
+  (disconnect-grid 'tripped))
+
+(defun reset-grid-fuse ()
+  (when (eq grid-state 'tripped)
+    (grid-fuse-add-event 'reset nil)
+    (reconnect-grid)))
+
+(defun island-update (site-power)
+  ;; Grid-forming batteries share the local load equally, within their
+  ;; bounds, so that no power flows through the grid connection point.
+  ;; Load they can't cover is reported as `unserved-load'.
+  (let* ((batteries (grid-forming-batteries))
+         (forming-power (seq-reduce '+
+                                    (seq-map (lambda (id) (eval (power-symbol-from-id id)))
+                                             batteries)
+                                    0.0))
+         (load (- site-power forming-power))
+         (target (/ (- 0.0 load) (length batteries)))
+         (served 0.0))
+    (dolist (id batteries)
+      (let ((power (min (eval (inclusion-upper-symbol-from-id id))
+                        (max (eval (inclusion-lower-symbol-from-id id))
+                             target))))
+        (set (power-symbol-from-id id) power)
+        (setq served (+ served power))))
+    (let ((unserved (max 0.0 (+ load served))))
+      (cond
+        ((and (> unserved 0.0) (equal unserved-load 0.0))
+         (log.warn (format "Grid-forming inverters can't cover the local load.  Unserved load: %s W."
+                           unserved)))
+        ((and (equal unserved 0.0) (> unserved-load 0.0))
+         (log.info "Local load is fully served again.")))
+      (setq unserved-load unserved))))
+
+(defun grid-fuse-update (rated-fuse-current currents ms-since-last-call)
+  ;; Heats up the fuse while the current on any phase is above its
+  ;; rating, at a rate given by the trip curve, and cools it down
+  ;; otherwise.  The fuse trips when it is fully heated up.
+  (let* ((current (seq-reduce 'max
+                              (seq-map (lambda (x) (max x (- 0.0 x))) currents)
+                              0.0))
+         (ratio (/ current rated-fuse-current))
+         (trip-time (grid-fuse-trip-time ratio)))
+    (if (> ratio 1.0)
+        (progn
+          (unless grid-overloaded
+            (log.warn (format "Grid fuse overloaded: %s A, rated %s A."
+                              currents rated-fuse-current))
+            (grid-fuse-add-event 'overload currents)
+            (setq grid-overloaded t))
+          (when trip-time
+            (setq grid-fuse-heat
+                  (+ grid-fuse-heat
+                     (/ ms-since-last-call (* 1000.0 (max trip-time 0.001))))))
+          (when (>= grid-fuse-heat 1.0)
+            (grid-fuse-trip currents)))
+      (when grid-overloaded
+        (log.info (format "Grid fuse overload cleared: %s A." currents))
+        (grid-fuse-add-event 'cleared currents)
+        (setq grid-overloaded nil))
+      (setq grid-fuse-heat
+            (max 0.0 (- grid-fuse-heat
+                        (/ ms-since-last-call grid-fuse-cool-down-ms)))))))
+
 (defun make-grid (&rest plist)
-  (let ((id (or (plist-get plist :id) (get-comp-id)))
-        (successors (plist-get plist :successors))
-        (rated-fuse-current (plist-get plist :rated-fuse-current))
-        (grid
-         `((category . grid)
-           (id       . ,id)
-           (name     . "grid")
-           (rated-fuse-current . ,rated-fuse-current))))
+  (let* ((id (or (plist-get plist :id) (get-comp-id)))
+         (successors (plist-get plist :successors))
+         (rated-fuse-current (plist-get plist :rated-fuse-current))
+         (current-expr (make-current-expr successors))
+         (power-expr (make-power-expr successors))
+         (grid
+          `((category . grid)
+            (id       . ,id)
+            (name     . "grid")
+            (rated-fuse-current . ,rated-fuse-current)
+            (current  . ,current-expr)
+            (power    . ,power-expr))))
 
     (log.trace (format "Adding grid connection %s" id))
 
+    (setq grid-meter-ids
+          (seq-map (lambda (s) (alist-get 'id s))
+                   (seq-filter (lambda (s) (eq (alist-get 'category s) 'meter))
+                               successors)))
+
+    (when power-expr
+      (setq state-update-functions
+            (cons (eval (list 'lambda '(ms-since-last-call)
+                              `(when (and (not (grid-connected-p)) islanded)
+                                 (island-update ,power-expr))))
+                  state-update-functions)))
+
+    (when (and rated-fuse-current current-expr)
+      (setq state-update-functions
+            (cons (eval (list 'lambda '(ms-since-last-call)
+                              `(when (grid-connected-p)
+                                 (grid-fuse-update ,rated-fuse-current
+                                                   ,current-expr
+                                                   ms-since-last-call))))
+                  state-update-functions)))
+
     (add-to-components-alist grid)
     (connect-successors id successors)
     grid))
@@ -0,0 +1,155 @@
+;; Defaults for all configs, which can override any of these.  This
+;; file is loaded after `reset-state', every time a config is
+;; (re)loaded: by `config.lisp' itself, and by the simulator for
+;; declarative topology files and `SimHarness' sources.
+
+
+;; Simulator configuration
+(setq state-update-interval-ms 200)
+
+
+;; API service config
+(setq socket-addr "[::1]:8800")  ;; Needs restart to take effect.
+(setq retain-requests-duration-ms 60000)
+;; Power requests to these kinds of components expire after
+;; `retain-requests-duration-ms', unless renewed.  Any of
+;; `battery-inverter', `solar-inverter', `ev-charger' and `chp'.
+(setq request-timeout-categories '(battery-inverter))
+;; When a power request expires, the component's power is set to
+;; zero (`zero'), kept (`hold'), set to a number, or the component is
+;; switched to standby (`standby').  `request-timeout-fallbacks'
+;; overrides it per component ID, like '((1003 . hold) (1005 . 5000.0)).
+(setq request-timeout-fallback 'zero)
+(setq request-timeout-fallbacks nil)
+;; When set, a client that controls a component, with a command in
+;; the last `retain-requests-duration-ms', is its only controller, and
+;; commands from other clients are rejected.
+(setq single-controller nil)
+(setq battery-interval 1000)
+(setq inverter-interval 1000)
+(setq meter-interval 200)
+(setq ev-charger-interval 1000)
+
+;; Failures injected into the API's unary calls, per method, like
+;; `set-power-active', `start', `stop', `list-components',
+;; `list-connections' or `get-microgrid-metadata'.  The optional
+;; `components' key limits the faults to calls for those components.
+(setq rpc-faults nil)
+(setq metadata nil)
+
+
+;; Simulation config
+
+;; This simulates the external factors that affect the microgrid,
+;; including the consumer power and the state of the grid.  The
+;; consumer power is `consumer-power-base' watts, with up to
+;; `consumer-power-noise' watts of noise.
+;;
+;; Recorded site consumption can be replayed instead, by loading it
+;; with `load-profile' and sampling it with `profile-value':
+;;
+;;   (setq consumer-profile
+;;         (load-profile "consumption.csv"
+;;                       :time-format 'timestamp  ;; or 'offset (seconds)
+;;                       :value-column 1
+;;                       :interpolation 'linear   ;; or 'step, 'nearest
+;;                       :loop t
+;;                       :time-scale 1.0))
+;;
+;; as the base of the consumer power:
+;;
+;;   (setq consumer-power-noise 0)
+;;   (every :milliseconds 200
+;;          :call (lambda ()
+;;                  (setq consumer-power-base
+;;                        (profile-value consumer-profile))))
+(setq consumer-power-base 48000)
+(setq consumer-power-noise 100)
+
+(every
+ :milliseconds 200
+ :call (lambda ()
+         (setq consumer-power
+               (+ consumer-power-base
+                  (if (> consumer-power-noise 0)
+                      (random consumer-power-noise)
+                    0)))
+
+         (setq voltage-per-phase
+               (grid-voltage-per-phase
+                (list (+ 229.0 (/ (random 200) 100.0))
+                      (+ 229.0 (/ (random 200) 100.0))
+                      (+ 229.0 (/ (random 200) 100.0)))))
+
+         (setq power-factor-per-phase
+               (list (+ 0.88 (/ (random 5) 100.0))
+                     (+ 0.88 (/ (random 5) 100.0))
+                     (+ 0.88 (/ (random 5) 100.0))))
+
+         (setq ac-frequency
+               (grid-frequency (+ 49.99 (/ (random 4) 100.0))))))
+
+;; Component defaults.  All these defaults can be overridden
+;; separately for individual components, if necessary.
+(setq battery-defaults '((initial-soc      . 90.0)
+                         (soc-lower        . 10.0)
+                         (soc-upper        . 90.0)
+                         (capacity         . 92000.0)
+                         (voltage          . 800.0)
+                         (rated-bounds     . (-30000.0 30000.0))
+                         (exclusion-bounds . (0.0 0.0))
+                         (component-state  . idle)
+                         (relay-state      . closed)))
+
+;; Meters can also have a `measurement', with sensor errors, see
+;; `config.lisp'.
+(setq meter-defaults '((component-state . ok)))
+
+;; Grid-forming battery inverters keep the site running as an island
+;; during a grid outage, which can be simulated with `(grid-outage)'
+;; and `(restore-grid)', or by stopping and starting the grid
+;; component through the API.
+;;
+;; Inverters with an `fcr-power' provide frequency containment, with a
+;; linear P(f) droop that fully activates `fcr-power' watts at a
+;; deviation of `fcr-full-activation' Hz from 50 Hz.
+(setq battery-inverter-defaults '((component-state     . idle)
+                                  (rated-bounds        . (-30000.0 30000.0))
+                                  (grid-forming        . nil)
+                                  (fcr-power           . nil)
+                                  (fcr-deadband        . 0.01)
+                                  (fcr-full-activation . 0.2)))
+
+(setq solar-inverter-defaults '((component-state . idle)
+                                (rated-bounds    . (-30000.0 0.0))))
+
+;; `phases' lists the grid phases an ev-charger is connected to, and
+;; the current limits can be a single value or one value per grid
+;; phase.  The rated power follows from the current limits, unless
+;; `rated-bounds' is given.
+(setq ev-charger-defaults '((initial-soc           . 50.0)
+                            (soc-lower             . 0.0)
+                            (soc-upper             . 100.0)
+                            (component-state       . ready)
+                            (cable-state           . ev-locked)
+                            (phases                . (1 2 3))
+                            (min-current-per-phase . 6.0)
+                            (max-current-per-phase . 16.0)
+                            (capacity              . 30000.0)))
+
+;; Defaults for ev-chargers created with `:sessions'.  EVs arrive
+;; randomly, at `arrivals-per-hour' on average, unless a `schedule'
+;; is given, as a list of alists like:
+;;
+;;   ((arrive . 60) (depart . 3600) (capacity . 60000.0)
+;;    (initial-soc . 20.0) (max-power . 11000.0))
+;;
+;; with times in seconds since the ev-charger was created.  Missing
+;; EV parameters are picked randomly from the ranges below.
+(setq ev-session-defaults '((arrivals-per-hour . 1.0)
+                            (stay-minutes      . (30.0 240.0))
+                            (ev-capacity       . (40000.0 80000.0))
+                            (ev-initial-soc    . (10.0 60.0))
+                            (ev-max-power      . (4200.0 11000.0))
+                            (lock-delay-ms     . 2000)
+                            (unplug-delay-ms   . 2000)))
@@ -0,0 +1,117 @@
+;; Scenarios run a timeline of actions and checks against the
+;; simulation, and finish with a pass/fail result.  A scenario file
+;; looks like this:
+;;
+;;   (scenario "Battery failure during outage"
+;;     (at 30 (grid-outage))
+;;     (at 35 (expect-within 5 (equal (component-value 2 'power) 0.0)
+;;                           "grid meter reads zero"))
+;;     (at (minutes 2)
+;;         (fail-component 1004)
+;;         (expect-always 10 (< (component-value 1003 'power) 30000.0))))
+;;
+;; Times are in seconds of simulated time since the scenario started.
+;; The scenario finishes when all actions have run and all checks
+;; have passed or failed.
+
+(setq scenario-name nil)
+(setq scenario-status nil)
+(setq scenario-time-ms 0)
+(setq scenario-start-ms 0)
+(setq scenario-steps nil)
+(setq scenario-checks nil)
+(setq scenario-results nil)
+(setq scenario-grid-events nil)
+
+(defun minutes (n)
+  (* 60 n))
+
+(defmacro at (seconds &rest forms)
+  `(cons (* 1000.0 ,seconds) (quote ,forms)))
+
+(defun scenario (name &rest steps)
+  (log.info (format "Starting scenario \"%s\"" name))
+  (setq scenario-name name)
+  (setq scenario-time-ms 0)
+  (setq scenario-start-ms simulation-time-ms)
+  (setq scenario-steps steps)
+  (setq scenario-checks nil)
+  (setq scenario-results nil)
+  (setq scenario-grid-events nil)
+  (setq scenario-status 'running))
+
+(defun scenario-add-check (kind seconds condition description)
+  (setq scenario-checks
+        (cons `((kind        . ,kind)
+                (deadline    . ,(+ scenario-time-ms (* 1000.0 seconds)))
+                (condition   . ,condition)
+                (description . ,(or description (format "%s" condition))))
+              scenario-checks)))
+
+;; The condition has to become true within `seconds'.
+(defmacro expect-within (seconds condition &optional description)
+  `(scenario-add-check 'within ,seconds (quote ,condition) ,description))
+
+;; The condition has to stay true for `seconds'.
+(defmacro expect-always (seconds condition &optional description)
+  `(scenario-add-check 'always ,seconds (quote ,condition) ,description))
+
+(defun scenario-record (check passed)
+  (let ((description (alist-get 'description check))
+        (seconds (/ scenario-time-ms 1000.0)))
+    (if passed
+        (log.info (format "PASS at %ss: %s" seconds description))
+      (log.error (format "FAIL at %ss: %s" seconds description)))
+    (setq scenario-results
+          `(,@scenario-results
+            ((description . ,description)
+             (passed      . ,passed)
+             (seconds     . ,seconds))))))
+
+(defun scenario-finish ()
+  (let ((passed 0)
+        (failed 0))
+    (dolist (result scenario-results)
+      (if (alist-get 'passed result)
+          (setq passed (+ passed 1))
+        (setq failed (+ failed 1))))
+    (setq scenario-status (if (> failed 0) 'failed 'passed))
+    (log.info (format "Scenario \"%s\" %s: %s checks passed, %s failed."
+                      scenario-name scenario-status passed failed))
+    (dolist (result scenario-results)
+      (log.info (format "  %s  %ss  %s"
+                        (if (alist-get 'passed result) "PASS" "FAIL")
+                        (alist-get 'seconds result)
+                        (alist-get 'description result))))))
+
+(defun scenario-tick (ms-since-last-call)
+  ;; Scenario time follows the simulated time, so scenarios run the same
+  ;; when time is stepped manually.
+  (setq scenario-time-ms (- simulation-time-ms scenario-start-ms))
+
+  (let ((pending nil))
+    (dolist (step scenario-steps)
+      (if (<= (car step) scenario-time-ms)
+          (dolist (form (cdr step))
+            (eval form))
+        (setq pending `(,@pending ,step))))
+    (setq scenario-steps pending))
+
+  (let ((pending nil))
+    (dolist (check scenario-checks)
+      (let ((kind (alist-get 'kind check))
+            (holds (eval (alist-get 'condition check)))
+            (expired (>= scenario-time-ms (alist-get 'deadline check))))
+        (cond
+          ((eq kind 'within)
+           (cond
+             (holds (scenario-record check t))
+             (expired (scenario-record check nil))
+             (t (setq pending (cons check pending)))))
+          ((not holds) (scenario-record check nil))
+          (expired (scenario-record check t))
+          (t (setq pending (cons check pending))))))
+    (setq scenario-checks pending))
+
+  (when (and (null scenario-steps) (null scenario-checks))
+    (scenario-finish)))
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+
+use serde_json::Value;
+
+use crate::{config::Config, sessions::Sessions, topology::Topology};
+
+#[derive(Clone)]
+struct AdminState {
+    config: Config,
+    sessions: Sessions,
+}
+
+/// Returns the current topology in the Graphviz DOT format, like the
+/// `export` subcommand.
+async fn handle_topology_dot(State(state): State<AdminState>) -> Response {
+    let topology = Topology::from_registry(&state.config.registry());
+    (
+        [(header::CONTENT_TYPE, "text/vnd.graphviz")],
+        topology.to_dot(),
+    )
+        .into_response()
+}
+
+/// Returns the current topology as JSON, like the `export` subcommand.
+async fn handle_topology_json(State(state): State<AdminState>) -> Response {
+    let topology = Topology::from_registry(&state.config.registry());
+    Json(topology.to_json()).into_response()
+}
+
+/// Returns the recent events, from the event ID in the `since`
+/// parameter on.
+async fn handle_events(
+    State(state): State<AdminState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let since = params
+        .get("since")
+        .and_then(|since| since.parse().ok())
+        .unwrap_or(0);
+    Json(state.config.events().since(since))
+}
+
+/// Returns the client that last commanded each component, and whether
+/// it still controls it.
+async fn handle_controllers(State(state): State<AdminState>) -> Response {
+    match (
+        state.config.simulation_time().await,
+        state.config.retain_requests_duration().await,
+    ) {
+        (Ok(now), Ok(duration)) => Json(state.sessions.to_json(now, duration)).into_response(),
+        (Err(status), _) | (_, Err(status)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            status.message().to_string(),
+        )
+            .into_response(),
+    }
+}
+
+/// Serves the admin API, for inspecting the simulation over HTTP.  The
+/// endpoints are plain HTTP GET requests, separate from the gRPC
+/// microgrid API.
+pub(crate) async fn serve(config: Config, sessions: Sessions, addr: String) {
+    let app = Router::new()
+        .route("/topology.dot", get(handle_topology_dot))
+        .route("/topology.json", get(handle_topology_json))
+        .route("/events", get(handle_events))
+        .route("/controllers", get(handle_controllers))
+        .with_state(AdminState { config, sessions });
+
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Unable to serve the admin API on {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Serving the admin API on http://{}/", addr);
+    if let Err(err) = axum::serve(listener, app).await {
+        log::error!("Admin API server failed: {}", err);
+    }
+}
@@ -0,0 +1,44 @@
+use tulisp::{Error, ErrorKind, TulispContext, TulispObject};
+
+/// Returns the value of `key` in a config alist, or nil.
+pub(crate) fn get(
+    ctx: &mut TulispContext,
+    alist: &TulispObject,
+    key: &str,
+) -> Result<TulispObject, Error> {
+    let key = ctx.intern(key);
+    tulisp::lists::alist_get(ctx, &key, alist, None, None, None)
+}
+
+/// Returns the number for `key` in a config alist, if it is set.
+pub(crate) fn get_float(
+    ctx: &mut TulispContext,
+    alist: &TulispObject,
+    key: &str,
+) -> Result<Option<f64>, Error> {
+    let value = get(ctx, alist, key)?;
+    if value.null() {
+        return Ok(None);
+    }
+    value.try_float().map(Some)
+}
+
+/// Parses a gRPC status name, like `unavailable`, for injected
+/// failures.
+pub(crate) fn status_code(name: &str) -> Result<tonic::Code, Error> {
+    Ok(match name {
+        "cancelled" => tonic::Code::Cancelled,
+        "unknown" => tonic::Code::Unknown,
+        "deadline-exceeded" => tonic::Code::DeadlineExceeded,
+        "resource-exhausted" => tonic::Code::ResourceExhausted,
+        "aborted" => tonic::Code::Aborted,
+        "internal" => tonic::Code::Internal,
+        "unavailable" => tonic::Code::Unavailable,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Unsupported gRPC status: {name}"),
+            ))
+        }
+    })
+}
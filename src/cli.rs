@@ -0,0 +1,198 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use tonic::transport::Server;
+
+use crate::{admin, config, metrics, proto, recorder, replay, server, topology, tui, web};
+
+#[derive(Parser)]
+#[command(about = "A microgrid simulator with a Frequenz microgrid API")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The config file to simulate.
+    #[arg(long, default_value = "config.lisp")]
+    config: String,
+
+    /// Run a scenario file against the simulation, and exit with its
+    /// result.
+    #[arg(long)]
+    scenario: Option<String>,
+
+    /// Record the streamed component data and the received commands to
+    /// a JSONL file.
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Serve the topology and component data from a recording made
+    /// with `--record`, instead of simulating them.
+    #[arg(long)]
+    replay: Option<String>,
+
+    /// Speed factor for `--replay`.
+    #[arg(long, default_value_t = 1.0)]
+    replay_speed: f64,
+
+    /// Serve Prometheus metrics on this address, like `[::1]:9100`.
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    /// Serve the admin API on this address, like `[::1]:8081`.
+    #[arg(long)]
+    admin_addr: Option<String>,
+
+    /// Serve a web page with the component graph and live charts on
+    /// this address, like `[::1]:8080`.
+    #[arg(long)]
+    web_addr: Option<String>,
+
+    /// Show a live dashboard of the components in the terminal.  Logs
+    /// are written to `microsim.log` instead.
+    #[arg(long)]
+    tui: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the topology built by the config, and exit.
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Dot)]
+        format: ExportFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Dot,
+    Json,
+}
+
+fn export(config: &config::Config, format: ExportFormat) {
+    let registry = config.registry();
+    if registry.components().components.is_empty() {
+        log::error!("Unable to export the topology: the config has no components");
+        std::process::exit(1);
+    }
+    let topology = topology::Topology::from_registry(&registry);
+    match format {
+        ExportFormat::Dot => print!("{}", topology.to_dot()),
+        ExportFormat::Json => println!("{:#}", topology.to_json()),
+    }
+}
+
+/// Runs the simulator with the command line arguments.
+pub async fn run() {
+    let args = Args::parse();
+
+    if let Some(Command::Export { format }) = args.command {
+        // Only errors are logged, to stderr, to keep the output clean.
+        simplelog::SimpleLogger::init(simplelog::LevelFilter::Error, simplelog::Config::default())
+            .unwrap();
+        export(&config::Config::new(&args.config).await, format);
+        return;
+    }
+
+    if args.tui {
+        simplelog::WriteLogger::init(
+            simplelog::LevelFilter::Debug,
+            simplelog::Config::default(),
+            std::fs::File::create("microsim.log").unwrap(),
+        )
+        .unwrap();
+    } else {
+        simplelog::SimpleLogger::init(simplelog::LevelFilter::Debug, simplelog::Config::default())
+            .unwrap();
+    }
+
+    let config = config::Config::new(&args.config).await;
+    if let Some(scenario) = &args.scenario {
+        if let Err(err) = config.load_scenario(scenario).await {
+            log::error!("Unable to load scenario {}: {}", scenario, err);
+            std::process::exit(2);
+        }
+    }
+    if let Err(status) = config.start().await {
+        log::error!("Unable to start the simulation: {}", status.message());
+        std::process::exit(2);
+    }
+    if let Some(addr) = args.metrics_addr.clone() {
+        tokio::spawn(metrics::serve(config.clone(), addr));
+    }
+    if let Some(addr) = args.web_addr.clone() {
+        tokio::spawn(web::serve(config.clone(), addr));
+    }
+    let socket_addr = config.socket_addr().await.unwrap_or_else(|status| {
+        log::error!("Unable to read socket-addr: {}", status.message());
+        std::process::exit(2);
+    });
+    log::info!("Server listening on {}", socket_addr);
+
+    let mut server = server::MicrogridServer::new(config.clone());
+    if let Some(filename) = &args.replay {
+        match replay::Replay::load(filename, args.replay_speed) {
+            Ok(replay) => server = server.with_replay(replay),
+            Err(err) => {
+                log::error!("Unable to load recording {}: {}", filename, err);
+                std::process::exit(2);
+            }
+        }
+    }
+    let mut recorder = None;
+    if let Some(filename) = &args.record {
+        match recorder::Recorder::new(filename).await {
+            Ok(rec) => {
+                server = server.with_recorder(rec.clone());
+                recorder = Some(rec);
+            }
+            Err(err) => {
+                log::error!("Unable to create recording {}: {}", filename, err);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(addr) = args.admin_addr.clone() {
+        tokio::spawn(admin::serve(config.clone(), server.sessions.clone(), addr));
+    }
+    let timeout_tracker = server.timeout_tracker.clone();
+    let server = Server::builder()
+        .add_service(proto::microgrid::microgrid_server::MicrogridServer::new(
+            server,
+        ))
+        .serve(socket_addr.parse().unwrap());
+    let tui = async {
+        if args.tui {
+            tui::run(config.clone(), timeout_tracker).await
+        } else {
+            std::future::pending().await
+        }
+    };
+    let scenario_result = async {
+        if args.scenario.is_some() {
+            config.scenario_result().await
+        } else {
+            std::future::pending().await
+        }
+    };
+
+    // Runs until the server fails, the user quits the dashboard, or the
+    // scenario ends, and then shuts down in order.
+    let code = tokio::select! {
+        res = server => match res {
+            Ok(()) => 0,
+            Err(err) => {
+                log::error!("Server failed: {}", err);
+                2
+            }
+        },
+        () = tui => 0,
+        passed = scenario_result => if passed.unwrap_or(false) { 0 } else { 1 },
+    };
+    if let Some(recorder) = recorder {
+        recorder.flush().await;
+    }
+    if let Err(err) = config.shutdown().await {
+        log::error!("{}", err);
+    }
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
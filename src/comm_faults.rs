@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tulisp::{Error, TulispContext, TulispObject};
+
+use crate::alist::{get, get_float, status_code};
+
+/// Communication impairments applied to a component's data stream.
+///
+/// Configured per component with the `:comm-faults` option, as an
+/// alist with these optional keys:
+///
+///   - `drop-rate`: probability of a sample being dropped.
+///   - `delay-ms`: latency added to every sample.
+///   - `jitter-ms`: additional random latency, up to this value.
+///   - `stale-rate`: probability of repeating the previous sample,
+///     with its old timestamp, instead of a new one.
+///   - `disconnect-after-s`: terminates the stream after this many
+///     seconds.
+///   - `disconnect-status`: the gRPC status to terminate the stream
+///     with, like `unavailable` (default) or `internal`.
+#[derive(Clone, Default)]
+pub(crate) struct CommFaults {
+    drop_rate: f64,
+    delay: Duration,
+    jitter: Duration,
+    stale_rate: f64,
+    disconnect_after: Option<Duration>,
+    disconnect_status: Option<tonic::Code>,
+}
+
+impl CommFaults {
+    pub(crate) fn from_alist(ctx: &mut TulispContext, alist: &TulispObject) -> Result<Self, Error> {
+        if alist.null() {
+            return Ok(Self::default());
+        }
+        let millis =
+            |ms: Option<f64>| Duration::from_secs_f64(ms.unwrap_or_default().max(0.0) / 1000.0);
+
+        let status = get(ctx, alist, "disconnect-status")?;
+        let disconnect_status = if status.null() {
+            None
+        } else {
+            Some(status_code(&status.as_symbol()?)?)
+        };
+
+        Ok(Self {
+            drop_rate: get_float(ctx, alist, "drop-rate")?.unwrap_or_default(),
+            delay: millis(get_float(ctx, alist, "delay-ms")?),
+            jitter: millis(get_float(ctx, alist, "jitter-ms")?),
+            stale_rate: get_float(ctx, alist, "stale-rate")?.unwrap_or_default(),
+            disconnect_after: get_float(ctx, alist, "disconnect-after-s")?
+                .map(|s| Duration::from_secs_f64(s.max(0.0))),
+            disconnect_status,
+        })
+    }
+
+    pub(crate) fn drop_sample(&self) -> bool {
+        self.drop_rate > 0.0 && rand::thread_rng().gen_bool(self.drop_rate.min(1.0))
+    }
+
+    pub(crate) fn stale_sample(&self) -> bool {
+        self.stale_rate > 0.0 && rand::thread_rng().gen_bool(self.stale_rate.min(1.0))
+    }
+
+    /// The latency to add to the next sample.
+    pub(crate) fn latency(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        self.delay + self.jitter.mul_f64(rand::thread_rng().gen())
+    }
+
+    /// Returns the status to terminate the stream with, if it has been
+    /// running for longer than `disconnect-after-s`.
+    pub(crate) fn disconnect(&self, running_for: Duration) -> Option<tonic::Status> {
+        let after = self.disconnect_after?;
+        if running_for < after {
+            return None;
+        }
+        Some(tonic::Status::new(
+            self.disconnect_status.unwrap_or(tonic::Code::Unavailable),
+            format!(
+                "Stream terminated after {}s by comm-faults",
+                after.as_secs_f64()
+            ),
+        ))
+    }
+}
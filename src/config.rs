@@ -0,0 +1,348 @@
+use std::{
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{oneshot, Notify},
+    task::AbortHandle,
+};
+
+use crate::{
+    comm_faults::CommFaults,
+    events::Events,
+    lisp::Simulation,
+    metrics::Metrics,
+    proto::microgrid::{ComponentData, ComponentList, ConnectionList, MicrogridMetadata},
+    registry::{Fallback, Registry, SharedRegistry},
+    rpc_faults::RpcFaults,
+};
+
+type Job = Box<dyn FnOnce(&Simulation) + Send>;
+
+/// An error from the simulation, formatted on the simulation thread.
+#[derive(Debug, Clone)]
+pub struct SimError {
+    desc: String,
+    message: String,
+}
+
+impl SimError {
+    pub(crate) fn new(desc: String, message: String) -> Self {
+        Self { desc, message }
+    }
+
+    /// The error message, without the lisp backtrace.
+    pub fn desc(&self) -> &str {
+        &self.desc
+    }
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<tonic::Status> for SimError {
+    fn from(status: tonic::Status) -> Self {
+        Self::new(status.message().to_string(), status.message().to_string())
+    }
+}
+
+impl From<SimError> for tonic::Status {
+    fn from(err: SimError) -> Self {
+        tonic::Status::internal(err.desc)
+    }
+}
+
+/// A handle to the simulation.
+///
+/// The lisp context isn't thread safe, so it lives on a dedicated
+/// thread, with its own runtime for the state updates and for watching
+/// the config file.  The handle sends calls to that thread, and can be
+/// shared freely between tasks and threads.  The component registry is
+/// read without going through the simulation thread.
+///
+/// Calls fail with `unavailable` once the simulation thread stopped,
+/// after `shutdown`.
+#[derive(Clone)]
+pub struct Config {
+    tx: tokio::sync::mpsc::UnboundedSender<Job>,
+    registry: SharedRegistry,
+    events: Arc<Events>,
+    metrics: Arc<Metrics>,
+    stop: Arc<Notify>,
+    thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Config {
+    fn spawn(
+        init: impl FnOnce(SharedRegistry, Arc<Events>, Arc<Metrics>) -> Result<Simulation, String>
+            + Send
+            + 'static,
+    ) -> (Self, oneshot::Receiver<Result<(), String>>) {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Job>();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let registry = SharedRegistry::default();
+        let sim_registry = registry.clone();
+        let events = Arc::new(Events::default());
+        let sim_events = events.clone();
+        let metrics = Arc::new(Metrics::default());
+        let sim_metrics = metrics.clone();
+        let stop = Arc::new(Notify::new());
+        let sim_stop = stop.clone();
+
+        let thread = std::thread::Builder::new()
+            .name("simulation".to_string())
+            .spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("Unable to create the simulation runtime");
+                let local = tokio::task::LocalSet::new();
+                local.block_on(&rt, async move {
+                    let sim = match init(sim_registry, sim_events, sim_metrics) {
+                        Ok(sim) => {
+                            let _ = ready_tx.send(Ok(()));
+                            sim
+                        }
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(err));
+                            return;
+                        }
+                    };
+                    loop {
+                        let job = tokio::select! {
+                            job = rx.recv() => job,
+                            _ = sim_stop.notified() => None,
+                        };
+                        let Some(job) = job else {
+                            break;
+                        };
+                        // A failing call only fails its caller, like a
+                        // panicking request handler.
+                        if catch_unwind(AssertUnwindSafe(|| job(&sim))).is_err() {
+                            log::error!("A call to the simulation panicked");
+                        }
+                    }
+                });
+            })
+            .expect("Unable to start the simulation thread");
+
+        let config = Self {
+            tx,
+            registry,
+            events,
+            metrics,
+            stop,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        };
+        (config, ready_rx)
+    }
+
+    /// Stops the simulation thread, with its state updates, and waits
+    /// for it to finish.  Calls from the remaining handles fail from
+    /// then on.
+    pub(crate) async fn shutdown(&self) -> Result<(), String> {
+        self.stop.notify_one();
+        let Some(thread) = self.thread.lock().unwrap().take() else {
+            return Ok(());
+        };
+        match tokio::task::spawn_blocking(move || thread.join()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err("The simulation thread panicked".to_string()),
+            Err(err) => Err(format!("Unable to join the simulation thread: {err}")),
+        }
+    }
+
+    /// Loads the config file, on a new simulation thread.  Errors in
+    /// the file are logged, and fixed by reloading it.
+    pub async fn new(filename: &str) -> Self {
+        let filename = filename.to_string();
+        let (config, ready) = Self::spawn(move |registry, events, metrics| {
+            Ok(Simulation::new(&filename, registry, events, metrics))
+        });
+        let _ = ready.await;
+        config
+    }
+
+    /// Creates a simulation from lisp source, instead of a file.  See
+    /// `Simulation::from_source`.
+    pub async fn from_source(source: &str) -> Result<Self, String> {
+        let source = source.to_string();
+        let (config, ready) = Self::spawn(move |registry, events, metrics| {
+            Simulation::from_source(&source, registry, events, metrics)
+        });
+        ready
+            .await
+            .map_err(|_| "The simulation thread stopped".to_string())??;
+        Ok(config)
+    }
+
+    /// Runs `func` on the simulation thread, and returns its result.
+    async fn call<T: Send + 'static>(
+        &self,
+        func: impl FnOnce(&Simulation) -> T + Send + 'static,
+    ) -> Result<T, tonic::Status> {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |sim| {
+            let _ = tx.send(func(sim));
+        });
+        self.tx
+            .send(job)
+            .map_err(|_| tonic::Status::unavailable("The simulation stopped"))?;
+        rx.await
+            .map_err(|_| tonic::Status::internal("The simulation call failed"))
+    }
+
+    /// Like `call`, for functions that return lisp errors.
+    async fn try_call<T: Send + 'static>(
+        &self,
+        func: impl FnOnce(&Simulation) -> Result<T, tulisp::Error> + Send + 'static,
+    ) -> Result<T, SimError> {
+        self.call(move |sim| func(sim).map_err(|err| sim.error(err)))
+            .await?
+    }
+
+    /// Starts the state updates, and reloading the config file when it
+    /// changes.
+    pub async fn start(&self) -> Result<(), tonic::Status> {
+        self.call(|sim| sim.start()).await
+    }
+
+    /// Starts only the state updates, which stop when the returned
+    /// handle is aborted.
+    pub(crate) async fn start_state_updates(&self) -> Result<AbortHandle, tonic::Status> {
+        self.call(|sim| sim.start_state_updates().abort_handle())
+            .await
+    }
+
+    /// Loads a scenario file, which starts running with the next state
+    /// update.
+    pub async fn load_scenario(&self, filename: &str) -> Result<(), String> {
+        let filename = filename.to_string();
+        self.call(move |sim| sim.load_scenario(&filename))
+            .await
+            .map_err(|status| status.message().to_string())?
+    }
+
+    /// Waits for the running scenario to finish, and returns whether it
+    /// passed.
+    pub async fn scenario_result(&self) -> Result<bool, tonic::Status> {
+        loop {
+            if let Some(passed) = self.call(|sim| sim.scenario_result()).await? {
+                return Ok(passed);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// The simulated time since the simulation started, which follows
+    /// the wall clock after `start`, and `step` otherwise.
+    pub(crate) async fn simulation_time(&self) -> Result<Duration, tonic::Status> {
+        self.call(|sim| sim.simulation_time()).await
+    }
+
+    /// Advances the simulation by `elapsed`, for when time is stepped
+    /// manually, instead of with `start`.
+    pub async fn step(&self, elapsed: Duration) -> Result<(), SimError> {
+        self.try_call(move |sim| sim.step(elapsed)).await
+    }
+
+    /// Evaluates lisp code in the simulation, and returns the printed
+    /// result.
+    pub async fn eval(&self, source: &str) -> Result<String, SimError> {
+        let source = source.to_string();
+        self.try_call(move |sim| sim.eval(&source)).await
+    }
+
+    pub async fn socket_addr(&self) -> Result<String, tonic::Status> {
+        self.call(|sim| sim.socket_addr()).await
+    }
+
+    pub async fn retain_requests_duration(&self) -> Result<Duration, tonic::Status> {
+        self.call(|sim| sim.retain_requests_duration()).await
+    }
+
+    pub async fn metadata(&self) -> Result<MicrogridMetadata, SimError> {
+        self.try_call(|sim| sim.metadata()).await
+    }
+
+    /// The component registry, as of the last successful (re)load.
+    pub(crate) fn registry(&self) -> Arc<Registry> {
+        self.registry.read().unwrap().clone()
+    }
+
+    /// The events of this simulation, from lisp and from the API.
+    pub(crate) fn events(&self) -> Arc<Events> {
+        self.events.clone()
+    }
+
+    /// The metrics of this simulation, for the `/metrics` endpoint.
+    pub(crate) fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn components(&self) -> ComponentList {
+        self.registry().components().clone()
+    }
+
+    pub fn connections(&self) -> ConnectionList {
+        self.registry().connections().clone()
+    }
+
+    pub(crate) async fn rpc_faults(
+        &self,
+        method: &str,
+        component_id: Option<u64>,
+    ) -> Result<RpcFaults, SimError> {
+        let method = method.to_string();
+        self.try_call(move |sim| sim.rpc_faults(&method, component_id))
+            .await
+    }
+
+    pub async fn set_power_active(&self, component_id: u64, power: f32) -> Result<(), SimError> {
+        self.try_call(move |sim| sim.set_power_active(component_id, power))
+            .await
+    }
+
+    pub async fn start_component(&self, component_id: u64) -> Result<(), SimError> {
+        self.try_call(move |sim| sim.start_component(component_id))
+            .await
+    }
+
+    pub async fn stop_component(&self, component_id: u64) -> Result<(), SimError> {
+        self.try_call(move |sim| sim.stop_component(component_id))
+            .await
+    }
+
+    /// Applies what happens to a component when its power request
+    /// expires.
+    pub(crate) async fn apply_fallback(
+        &self,
+        component_id: u64,
+        fallback: Fallback,
+    ) -> Result<(), SimError> {
+        match fallback {
+            Fallback::Zero => self.set_power_active(component_id, 0.0).await,
+            Fallback::Hold => Ok(()),
+            Fallback::Setpoint(power) => self.set_power_active(component_id, power).await,
+            Fallback::Standby => {
+                self.try_call(move |sim| sim.standby_component(component_id))
+                    .await
+            }
+        }
+    }
+
+    pub(crate) async fn get_component_data(
+        &self,
+        component_id: u64,
+    ) -> Result<(ComponentData, u64, CommFaults), tonic::Status> {
+        self.call(move |sim| sim.get_component_data(component_id))
+            .await?
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use serde_json::{json, Map, Value};
+use tulisp::{destruct_bind, Error, ErrorKind, TulispContext, TulispObject};
+
+/// How many events are kept, for clients that poll for them.
+const MAX_EVENTS: usize = 1000;
+
+struct Event {
+    id: u64,
+    ts: SystemTime,
+    kind: String,
+    component_id: Option<u64>,
+    details: Value,
+}
+
+impl Event {
+    fn to_json(&self) -> Value {
+        let ts = self
+            .ts
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        json!({
+            "id": self.id,
+            "ts": ts,
+            "kind": self.kind,
+            "component_id": self.component_id,
+            "details": self.details,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Log {
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+/// Things that happen in the simulator, that clients of the microgrid
+/// API can't see otherwise, like expired power requests.  The recent
+/// ones are served on the admin API's `/events` endpoint.  Each
+/// simulation has its own, from `Config::events`.
+#[derive(Default)]
+pub(crate) struct Events {
+    log: Mutex<Log>,
+}
+
+impl Events {
+    pub(crate) fn push(&self, kind: &str, component_id: Option<u64>, details: Value) {
+        let mut log = self.log.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        if log.events.len() == MAX_EVENTS {
+            log.events.pop_front();
+        }
+        log.events.push_back(Event {
+            id,
+            ts: SystemTime::now(),
+            kind: kind.to_string(),
+            component_id,
+            details,
+        });
+    }
+
+    /// Returns the events with IDs from `since` on, oldest first.
+    pub(crate) fn since(&self, since: u64) -> Value {
+        let log = self.log.lock().unwrap();
+        Value::Array(
+            log.events
+                .iter()
+                .filter(|event| event.id >= since)
+                .map(Event::to_json)
+                .collect(),
+        )
+    }
+}
+
+/// Converts a lisp value to JSON, for event details.  Alists become
+/// objects, other lists become arrays, and symbols become strings.
+fn to_json(obj: &TulispObject) -> Value {
+    if obj.null() {
+        Value::Null
+    } else if let Ok(int) = obj.as_int() {
+        int.into()
+    } else if let Ok(float) = obj.as_float() {
+        float.into()
+    } else if let Ok(string) = obj.as_string() {
+        string.into()
+    } else if let Ok(symbol) = obj.as_symbol() {
+        symbol.into()
+    } else if obj.consp() {
+        let is_alist = obj
+            .base_iter()
+            .all(|x| x.consp() && x.car().is_ok_and(|key| key.symbolp()));
+        if is_alist {
+            let mut map = Map::new();
+            for entry in obj.base_iter() {
+                if let (Ok(key), Ok(value)) = (entry.car().and_then(|k| k.as_symbol()), entry.cdr())
+                {
+                    map.insert(key, to_json(&value));
+                }
+            }
+            Value::Object(map)
+        } else {
+            Value::Array(obj.base_iter().map(|x| to_json(&x)).collect())
+        }
+    } else {
+        obj.to_string().into()
+    }
+}
+
+pub(crate) fn add_functions(ctx: &mut TulispContext, events: Arc<Events>) {
+    // (push-event KIND &optional COMPONENT-ID DETAILS) reports an event
+    // from lisp, with an alist of details.
+    ctx.add_special_form("push-event", move |ctx, args| {
+        destruct_bind!((kind &optional component_id details) = args);
+        let kind = ctx.eval(&kind)?.as_symbol()?;
+        let component_id = ctx.eval(&component_id)?;
+        let component_id = if component_id.null() {
+            None
+        } else {
+            Some(component_id.as_int()? as u64)
+        };
+        let details = to_json(&ctx.eval(&details)?);
+        events.push(&kind, component_id, details);
+        Ok(TulispObject::nil())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_lisp_to_json() {
+        let mut ctx = TulispContext::new();
+        let value = ctx
+            .eval_string(r#"'((event . trip) (currents . (1 2.5 3)) (note . "hot") (reset . nil))"#)
+            .unwrap();
+        assert_eq!(
+            to_json(&value),
+            json!({"event": "trip", "currents": [1, 2.5, 3], "note": "hot", "reset": null})
+        );
+    }
+}
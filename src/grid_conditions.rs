@@ -0,0 +1,320 @@
+use std::{any::Any, rc::Rc};
+
+use tulisp::{destruct_bind, Error, ErrorKind, TulispContext, TulispObject};
+
+const NOMINAL_FREQUENCY: f64 = 50.0;
+
+#[derive(Clone, Copy)]
+enum Quantity {
+    Frequency,
+    /// Voltage of all phases, or of a single phase (0-based).
+    Voltage(Option<usize>),
+}
+
+/// A scripted change in grid conditions.
+///
+/// Events ramp from the base value towards their target, hold the
+/// target for `duration`, and ramp back again.  Events without a
+/// duration hold their target until they are removed.
+pub(crate) struct GridEvent {
+    quantity: Quantity,
+    /// Hz for frequency events, or a factor of the base value for
+    /// voltage events.
+    target: f64,
+    /// Seconds of simulated time, since the simulation started.
+    start: f64,
+    ramp: f64,
+    duration: Option<f64>,
+}
+
+impl GridEvent {
+    /// How far the event has moved from the base value towards its
+    /// target, between 0.0 and 1.0.
+    fn progress(&self, now: f64) -> f64 {
+        if now < self.start {
+            return 0.0;
+        }
+        let elapsed = now - self.start;
+        let ramp_up = if self.ramp > 0.0 {
+            (elapsed / self.ramp).min(1.0)
+        } else {
+            1.0
+        };
+        let Some(duration) = self.duration else {
+            return ramp_up;
+        };
+        let since_end = elapsed - self.ramp - duration;
+        if since_end <= 0.0 {
+            ramp_up
+        } else if self.ramp > 0.0 {
+            (1.0 - since_end / self.ramp).max(0.0)
+        } else {
+            0.0
+        }
+    }
+
+    fn apply(&self, base: f64, now: f64) -> f64 {
+        let progress = self.progress(now);
+        match self.quantity {
+            Quantity::Frequency => base + (self.target - base) * progress,
+            Quantity::Voltage(_) => base * (1.0 + (self.target - 1.0) * progress),
+        }
+    }
+}
+
+/// Returns the FCR power in watts for the given grid frequency, with
+/// positive values for charging.
+///
+/// The response is linear between the deadband and the full activation
+/// deviation, at which `fcr_power` is fully activated.
+pub(crate) fn droop_power(
+    frequency: f64,
+    fcr_power: f64,
+    deadband: f64,
+    full_activation: f64,
+) -> f64 {
+    let deviation = frequency - NOMINAL_FREQUENCY;
+    if deviation.abs() <= deadband {
+        return 0.0;
+    }
+    let activation = (deviation / full_activation).clamp(-1.0, 1.0);
+    fcr_power * activation
+}
+
+/// Seconds of simulated time since the simulation started, from
+/// `simulation-time-ms`.
+fn simulation_time(ctx: &mut TulispContext) -> f64 {
+    ctx.intern("simulation-time-ms")
+        .get()
+        .and_then(|ms| ms.try_float())
+        .unwrap_or_default()
+        / 1000.0
+}
+
+/// Parses the options of a `grid-event`, which starts `:after` seconds
+/// after `anchor`.
+fn parse_event(
+    ctx: &mut TulispContext,
+    plist: &TulispObject,
+    anchor: f64,
+) -> Result<GridEvent, Error> {
+    let mut quantity = None;
+    let mut target = None;
+    let mut phase = None;
+    let mut after = 0.0;
+    let mut ramp = 0.0;
+    let mut duration = None;
+
+    let mut rest = plist.clone();
+    while rest.consp() {
+        let key = rest.car()?.as_symbol()?;
+        let val = ctx.eval(&rest.cadr()?)?;
+        rest = rest.cddr()?;
+
+        match key.as_str() {
+            ":frequency" => {
+                quantity = Some(Quantity::Frequency);
+                target = Some(val.try_float()?);
+            }
+            ":voltage" => {
+                quantity = Some(Quantity::Voltage(None));
+                target = Some(val.try_float()?);
+            }
+            ":phase" => phase = Some(val.as_int()?),
+            ":after" => after = val.try_float()?,
+            ":ramp" => ramp = val.try_float()?,
+            ":duration" => duration = Some(val.try_float()?),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Undefined,
+                    format!("Unknown grid-event option: {key}"),
+                ))
+            }
+        }
+    }
+
+    let (Some(quantity), Some(target)) = (quantity, target) else {
+        return Err(Error::new(
+            ErrorKind::Undefined,
+            "grid-event needs a :frequency or a :voltage".to_string(),
+        ));
+    };
+    let quantity = match (quantity, phase) {
+        (Quantity::Voltage(_), Some(phase @ 1..=3)) => Quantity::Voltage(Some(phase as usize - 1)),
+        (_, Some(phase)) => {
+            return Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Invalid phase for grid-event: {phase}"),
+            ))
+        }
+        (quantity, None) => quantity,
+    };
+
+    Ok(GridEvent {
+        quantity,
+        target,
+        start: anchor + after.max(0.0),
+        ramp,
+        duration,
+    })
+}
+
+/// Applies the events in the `grid-events` and `scenario-grid-events`
+/// lists of the given quantity to `base`, oldest first, so that later
+/// events take precedence.
+fn apply_events(
+    ctx: &mut TulispContext,
+    base: f64,
+    matches: impl Fn(Quantity) -> bool,
+) -> Result<f64, Error> {
+    let now = simulation_time(ctx);
+    let mut value = base;
+    for list in ["grid-events", "scenario-grid-events"] {
+        let events = ctx.intern(list).get().unwrap_or_default();
+        let events = events
+            .base_iter()
+            .map(|event| event.as_any())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for event in events.iter().rev() {
+            if let Some(event) = event.downcast_ref::<GridEvent>() {
+                if matches(event.quantity) {
+                    value = event.apply(value, now);
+                }
+            }
+        }
+    }
+    Ok(value)
+}
+
+pub(crate) fn add_functions(ctx: &mut TulispContext) {
+    ctx.add_special_form("grid-event", |ctx, args| {
+        destruct_bind!((&rest plist) = args);
+        // Events from the config start `:after` seconds after the
+        // simulation started, so that reloading the config recreates
+        // them unchanged.  Events from scenario actions start `:after`
+        // seconds after the action, and are kept across reloads.
+        let status = ctx.intern("scenario-status").get().unwrap_or_default();
+        let (list, anchor) = if status.eq(&ctx.intern("running")) {
+            ("scenario-grid-events", simulation_time(ctx))
+        } else {
+            ("grid-events", 0.0)
+        };
+        let event = parse_event(ctx, plist, anchor)?;
+        let events = ctx.intern(list);
+        let list = TulispObject::cons(
+            TulispObject::from(Rc::new(event) as Rc<dyn Any>),
+            events.get().unwrap_or_default(),
+        );
+        events.set(list)?;
+        Ok(TulispObject::nil())
+    });
+
+    ctx.add_special_form("grid-frequency", |ctx, args| {
+        destruct_bind!((base) = args);
+        let base = ctx.eval(&base)?.try_float()?;
+        let frequency = apply_events(ctx, base, |q| matches!(q, Quantity::Frequency))?;
+        Ok(frequency.into())
+    });
+
+    ctx.add_special_form("grid-voltage-per-phase", |ctx, args| {
+        destruct_bind!((base) = args);
+        let base = ctx.eval(&base)?;
+        let mut voltages = Vec::with_capacity(3);
+        for (phase, voltage) in base.base_iter().enumerate() {
+            let voltage = voltage.try_float()?;
+            voltages.push(apply_events(ctx, voltage, |q| match q {
+                Quantity::Voltage(None) => true,
+                Quantity::Voltage(Some(p)) => p == phase,
+                Quantity::Frequency => false,
+            })?);
+        }
+        let list = TulispObject::nil();
+        for voltage in voltages {
+            list.push(voltage.into())?;
+        }
+        Ok(list)
+    });
+
+    ctx.add_special_form("fcr-droop", |ctx, args| {
+        destruct_bind!((frequency fcr_power &optional deadband full_activation) = args);
+        let frequency = ctx.eval(&frequency)?.try_float()?;
+        let fcr_power = ctx.eval(&fcr_power)?.try_float()?;
+        let deadband = if deadband.null() {
+            0.01
+        } else {
+            ctx.eval(&deadband)?.try_float()?
+        };
+        let full_activation = if full_activation.null() {
+            0.2
+        } else {
+            ctx.eval(&full_activation)?.try_float()?
+        };
+        Ok(droop_power(frequency, fcr_power, deadband, full_activation).into())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn droop_is_zero_within_the_deadband() {
+        assert_eq!(droop_power(50.0, 1000.0, 0.01, 0.2), 0.0);
+        assert_eq!(droop_power(50.01, 1000.0, 0.01, 0.2), 0.0);
+        assert_eq!(droop_power(49.99, 1000.0, 0.01, 0.2), 0.0);
+    }
+
+    #[test]
+    fn droop_charges_on_overfrequency() {
+        assert!((droop_power(50.1, 1000.0, 0.01, 0.2) - 500.0).abs() < 1e-6);
+        assert!((droop_power(49.9, 1000.0, 0.01, 0.2) + 500.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn droop_saturates_at_full_activation() {
+        assert_eq!(droop_power(50.2, 1000.0, 0.01, 0.2), 1000.0);
+        assert_eq!(droop_power(51.0, 1000.0, 0.01, 0.2), 1000.0);
+        assert_eq!(droop_power(48.0, 1000.0, 0.01, 0.2), -1000.0);
+    }
+
+    #[test]
+    fn events_ramp_hold_and_ramp_back() {
+        let event = GridEvent {
+            quantity: Quantity::Frequency,
+            target: 49.0,
+            start: 10.0,
+            ramp: 2.0,
+            duration: Some(5.0),
+        };
+        assert_eq!(event.apply(50.0, 5.0), 50.0);
+        assert_eq!(event.apply(50.0, 11.0), 49.5);
+        assert_eq!(event.apply(50.0, 15.0), 49.0);
+        assert_eq!(event.apply(50.0, 18.0), 49.5);
+        assert_eq!(event.apply(50.0, 20.0), 50.0);
+    }
+
+    #[test]
+    fn config_events_are_anchored_at_the_simulation_start() {
+        let mut ctx = TulispContext::new();
+        add_functions(&mut ctx);
+        let frequency = |ctx: &mut TulispContext| {
+            ctx.eval_string("(grid-frequency 50.0)")
+                .unwrap()
+                .try_float()
+                .unwrap()
+        };
+        ctx.eval_string("(setq simulation-time-ms 20000)").unwrap();
+        ctx.eval_string("(grid-event :frequency 49.8 :after 10)")
+            .unwrap();
+        assert_eq!(frequency(&mut ctx), 49.8);
+
+        // Events from a running scenario start after the action.
+        ctx.eval_string("(setq scenario-status 'running)").unwrap();
+        ctx.eval_string("(grid-event :frequency 50.2 :after 10)")
+            .unwrap();
+        assert_eq!(frequency(&mut ctx), 49.8);
+        ctx.eval_string("(setq simulation-time-ms 30000)").unwrap();
+        assert_eq!(frequency(&mut ctx), 50.2);
+    }
+}
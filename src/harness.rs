@@ -0,0 +1,196 @@
+use std::{net::SocketAddr, str::FromStr, time::Duration};
+
+use tokio::{
+    sync::oneshot,
+    task::{AbortHandle, JoinHandle},
+};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+use crate::{
+    config::{Config, SimError},
+    proto::microgrid::microgrid_server,
+    server::{expire_requests, MicrogridServer},
+    sessions::Sessions,
+    timeout_tracker::TimeoutTracker,
+};
+
+/// Starts a simulated microgrid in-process, for integration tests.
+///
+/// The config is lisp source that builds the components, like the
+/// `make-grid` call in `config.lisp`.  The simulator's lisp files and
+/// the defaults from `sim/defaults.lisp` are preloaded, and can be
+/// overridden by the source.  The server listens on an ephemeral port
+/// on localhost, unless another address is given.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), String> {
+/// let sim = microsim::SimHarness::from_source(
+///     "(make-grid :id 1 :successors (list (make-meter :power 1000.0)))",
+/// )
+/// .manual_time()
+/// .spawn()
+/// .await?;
+///
+/// sim.step(std::time::Duration::from_secs(1))
+///     .await
+///     .map_err(|e| e.to_string())?;
+/// let endpoint = sim.endpoint();
+/// // ... connect to `endpoint` with a microgrid client.
+/// sim.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SimHarness {
+    source: String,
+    addr: SocketAddr,
+    manual_time: bool,
+}
+
+/// A running simulation, started with [`SimHarness::spawn`].
+pub struct SimHandle {
+    addr: SocketAddr,
+    config: Config,
+    shutdown_tx: oneshot::Sender<()>,
+    server: JoinHandle<Result<(), tonic::transport::Error>>,
+    state_updates: Option<AbortHandle>,
+    /// The server's power requests, expired by `step` too.
+    timeout_tracker: TimeoutTracker,
+    sessions: Sessions,
+}
+
+impl SimHarness {
+    pub fn from_source(source: &str) -> Self {
+        Self {
+            source: source.to_string(),
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            manual_time: false,
+        }
+    }
+
+    /// Listens on `addr` instead of an ephemeral port.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Only advances the simulation with [`SimHandle::step`], instead
+    /// of in real time.  Power requests expire on the stepped time
+    /// too.
+    pub fn manual_time(mut self) -> Self {
+        self.manual_time = true;
+        self
+    }
+
+    /// Loads the config and starts serving the microgrid API.  Has to
+    /// be called from within a tokio runtime, which can be single or
+    /// multi-threaded.
+    pub async fn spawn(self) -> Result<SimHandle, String> {
+        let config = Config::from_source(&self.source).await?;
+        let listener = tokio::net::TcpListener::bind(self.addr)
+            .await
+            .map_err(|e| format!("Unable to listen on {}: {e}", self.addr))?;
+        let addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+        let state_updates = if self.manual_time {
+            None
+        } else {
+            Some(
+                config
+                    .start_state_updates()
+                    .await
+                    .map_err(|status| status.message().to_string())?,
+            )
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let microgrid = MicrogridServer::new(config.clone());
+        let timeout_tracker = microgrid.timeout_tracker.clone();
+        let sessions = microgrid.sessions.clone();
+        let server = tokio::spawn(
+            Server::builder()
+                .add_service(microgrid_server::MicrogridServer::new(microgrid))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    let _ = shutdown_rx.await;
+                }),
+        );
+
+        Ok(SimHandle {
+            addr,
+            config,
+            shutdown_tx,
+            server,
+            state_updates,
+            timeout_tracker,
+            sessions,
+        })
+    }
+}
+
+impl FromStr for SimHarness {
+    type Err = std::convert::Infallible;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_source(source))
+    }
+}
+
+impl SimHandle {
+    /// The address the microgrid API is served on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of the microgrid API, for connecting clients to.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Advances the simulation by `elapsed`.  Power requests that
+    /// expired in that time have fallen back when it returns.
+    pub async fn step(&self, elapsed: Duration) -> Result<(), SimError> {
+        self.config.step(elapsed).await?;
+        expire_requests(&self.config, &self.timeout_tracker, &self.sessions).await?;
+        Ok(())
+    }
+
+    /// Loads a scenario file, which runs on the simulated time.
+    pub async fn load_scenario(&self, filename: &str) -> Result<(), String> {
+        self.config.load_scenario(filename).await
+    }
+
+    /// Evaluates lisp code in the simulation, and returns the printed
+    /// result.  The components are rebuilt if the code set
+    /// `registry-changed`.
+    pub async fn eval(&self, source: &str) -> Result<String, SimError> {
+        self.config.eval(source).await
+    }
+
+    /// Returns the printed value of a lisp variable.
+    pub async fn get(&self, name: &str) -> Result<String, SimError> {
+        self.config.eval(name).await
+    }
+
+    /// Sets a lisp variable to the value of a lisp expression.
+    pub async fn set(&self, name: &str, value: &str) -> Result<(), SimError> {
+        self.config
+            .eval(&format!("(setq {name} {value})"))
+            .await
+            .map(|_| ())
+    }
+
+    /// Stops the server and the simulation.
+    pub async fn shutdown(self) {
+        if let Some(state_updates) = self.state_updates {
+            state_updates.abort();
+        }
+        let _ = self.shutdown_tx.send(());
+        match self.server.await {
+            Ok(Err(err)) => log::error!("Server failed: {}", err),
+            Err(err) => log::error!("Server task failed: {}", err),
+            Ok(Ok(())) => {}
+        }
+        if let Err(err) = self.config.shutdown().await {
+            log::error!("{}", err);
+        }
+    }
+}
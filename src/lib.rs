@@ -0,0 +1,32 @@
+//! A microgrid simulator with a Frequenz microgrid API.
+//!
+//! Besides the `microsim` binary, the simulator can be started
+//! in-process, for integration tests, with [`SimHarness`].
+
+mod admin;
+mod alist;
+pub mod cli;
+mod comm_faults;
+mod config;
+mod events;
+mod grid_conditions;
+mod harness;
+mod lisp;
+mod measurement;
+mod metrics;
+mod profile;
+pub mod proto;
+mod recorder;
+mod registry;
+mod replay;
+mod rpc_faults;
+mod server;
+mod sessions;
+mod timeout_tracker;
+mod topology;
+mod topology_file;
+mod tui;
+mod web;
+
+pub use config::SimError;
+pub use harness::{SimHandle, SimHarness};
@@ -1,11 +1,26 @@
 use rand::Rng;
-use std::{cell::RefCell, collections::HashMap, path::Path, rc::Rc, str::FromStr, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
+use crate::comm_faults::CommFaults;
+use crate::events::Events;
+use crate::measurement::{MeasurementModel, Quantity};
+use crate::metrics::Metrics;
 use crate::proto::{
     common::{
         components::{BatteryType, ComponentCategory, EvChargerType, InverterType},
         metrics::{
-            electrical::{ac::AcPhase, Ac, Dc},
+            electrical::{
+                ac::{AcPhase, Energy},
+                Ac, Dc,
+            },
             Bounds, Metric, MetricAggregation,
         },
     },
@@ -14,12 +29,22 @@ use crate::proto::{
         ComponentData, ComponentList, Connection, ConnectionList, MicrogridMetadata, Location,
     },
 };
+use crate::registry::{Fallback, Registry, SharedRegistry, Stream, TimeoutCategory};
+use crate::rpc_faults::RpcFaults;
 use notify::{RecommendedWatcher, Watcher};
 use prost_types::Timestamp;
 use tulisp::{destruct_bind, intern, list, Error, ErrorKind, TulispContext, TulispObject};
 
-type CompDataMaker =
-    fn(&mut TulispContext, &TulispObject, &Symbols) -> Result<ComponentData, Error>;
+type CompDataMaker = fn(
+    &mut TulispContext,
+    &TulispObject,
+    &Symbols,
+    Option<&MeasurementModel>,
+) -> Result<ComponentData, Error>;
+
+/// Component ID -> (Component's Data Method, Interval, To ComponentData Method,
+/// Comm Faults)
+type StreamMethods = HashMap<u64, (TulispObject, u64, CompDataMaker, CommFaults)>;
 
 intern! {
     #[derive(Clone)]
@@ -34,53 +59,93 @@ intern! {
         voltage: "voltage",
         current: "current",
         category: "category",
+        comm_faults: "comm-faults",
         interval: "interval",
         capacity: "capacity",
         location: "location",
         metadata: "metadata",
+        measurement: "measurement",
         soc_lower: "soc-lower",
         soc_upper: "soc-upper",
         relay_state: "relay-state",
         cable_state: "cable-state",
         socket_addr: "socket-addr",
+        scenario_status: "scenario-status",
+        reload_requested: "reload-requested",
+        registry_changed: "registry-changed",
         ac_frequency: "ac-frequency",
         microgrid_id: "microgrid-id",
         inclusion_lower: "inclusion-lower",
         inclusion_upper: "inclusion-upper",
         exclusion_lower: "exclusion-lower",
         exclusion_upper: "exclusion-upper",
+        energy_consumed: "energy-consumed",
+        energy_delivered: "energy-delivered",
         per_phase_power: "per-phase-power",
         component_state: "component-state",
         components_alist: "components-alist",
+        hidden_components_alist: "hidden-components-alist",
         set_power_active: "set-power-active",
+        stop_component: "stop-component",
+        scenario_tick: "scenario-tick",
+        simulation_time_ms: "simulation-time-ms",
+        start_component: "start-component",
         connections_alist: "connections-alist",
+        hidden_connections_alist: "hidden-connections-alist",
         rated_fuse_current: "rated-fuse-current",
         state_update_functions: "state-update-functions",
         state_update_interval_ms: "state-update-interval-ms",
         retain_requests_duration_ms: "retain-requests-duration-ms",
+        request_timeout_categories: "request-timeout-categories",
+        request_timeout_fallback_for: "request-timeout-fallback-for",
+        standby_component: "standby-component",
+        single_controller: "single-controller",
     }
 }
 
+/// Where a config is loaded from, and reloaded from.
+#[derive(Clone)]
+enum ConfigSource {
+    File(String),
+    /// Lisp source, which builds components on top of the defaults in
+    /// `sim/defaults.lisp`.
+    Lisp(Rc<str>),
+}
+
+/// The simulator's lisp files, for configs that are not loaded from
+/// the repository's directory.
+const SIM_FILES: [(&str, &str); 3] = [
+    ("sim/common.lisp", include_str!("../sim/common.lisp")),
+    ("sim/components.lisp", include_str!("../sim/components.lisp")),
+    ("sim/scenario.lisp", SIM_SCENARIO),
+];
+/// Loaded with each scenario, so that scenarios don't depend on the
+/// working directory either.
+const SIM_SCENARIO: &str = include_str!("../sim/scenario.lisp");
+const SIM_DEFAULTS: &str = include_str!("../sim/defaults.lisp");
+
+/// The lisp simulation.  It lives on the simulation thread, and is
+/// accessed from elsewhere through `crate::config::Config`.
 #[derive(Clone)]
-pub struct Config {
-    filename: String,
+pub(crate) struct Simulation {
+    source: ConfigSource,
 
-    pub(crate) ctx: Rc<RefCell<tulisp::TulispContext>>,
+    ctx: Rc<RefCell<tulisp::TulispContext>>,
 
-    /// Component ID -> (Component's Data Method, Interval, To ComponentData Method)
-    stream_methods: Rc<RefCell<HashMap<u64, (TulispObject, u64, CompDataMaker)>>>,
+    /// The stream methods of the components in `registry`, rebuilt
+    /// together with it.
+    stream_methods: Rc<RefCell<StreamMethods>>,
+
+    registry: SharedRegistry,
 
     /// Component ID -> last power update time.
     last_formula_update_time: Rc<RefCell<std::time::Instant>>,
 
+    metrics: Arc<Metrics>,
+
     symbols: Symbols,
 }
 
-// Tokio is configured to use the current_thread runtime, so it is not unsafe to
-// make `Config` Send and Sync.
-unsafe impl Send for Config {}
-unsafe impl Sync for Config {}
-
 macro_rules! alist_get_as {
     ($ctx: expr, $rest:expr, $key:expr, $as_fn:ident) => {{
         alist_get_as!($ctx, $rest, $key).and_then(|x| x.$as_fn())
@@ -197,53 +262,260 @@ fn make_component_from_alist(
     Ok(comp)
 }
 
-impl Config {
-    pub fn new(filename: &str) -> Self {
+/// Evaluates a config file, which is either lisp, or a declarative
+/// topology file that is translated to lisp.
+fn eval_config_file(ctx: &mut TulispContext, filename: &str) -> Result<(), Error> {
+    let Some(format) = crate::topology_file::Format::of(filename) else {
+        return ctx.eval_file(filename).map(|_| ());
+    };
+    let source = std::fs::read_to_string(filename).map_err(|e| {
+        Error::new(ErrorKind::Undefined, format!("Unable to read {filename}: {e}"))
+    })?;
+    let source = crate::topology_file::to_lisp(&source, format)
+        .map_err(|e| Error::new(ErrorKind::ParsingError, format!("{filename}: {e}")))?;
+    // Like sources from `from_source`, translated topology files use
+    // the embedded lisp files, and don't depend on the working directory.
+    if !ctx.intern("simulator-loaded").boundp() {
+        for (_, lisp) in SIM_FILES {
+            ctx.eval_string(lisp)?;
+        }
+        ctx.eval_string("(setq simulator-loaded t)")?;
+    }
+    eval_with_defaults(ctx, &source)
+}
+
+/// Evaluates lisp source on top of the defaults in `sim/defaults.lisp`.
+fn eval_with_defaults(ctx: &mut TulispContext, source: &str) -> Result<(), Error> {
+    ctx.eval_string("(reset-state)")?;
+    ctx.eval_string(SIM_DEFAULTS)?;
+    ctx.eval_string(source).map(|_| ())
+}
+
+impl ConfigSource {
+    fn eval(&self, ctx: &mut TulispContext) -> Result<(), Error> {
+        match self {
+            ConfigSource::File(filename) => eval_config_file(ctx, filename),
+            ConfigSource::Lisp(source) => eval_with_defaults(ctx, source),
+        }
+    }
+}
+
+impl Simulation {
+    pub(crate) fn new(
+        filename: &str,
+        registry: SharedRegistry,
+        events: Arc<Events>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let mut ctx = tulisp::TulispContext::new();
-        add_functions(&mut ctx);
+        add_functions(&mut ctx, events);
 
-        let _ = ctx.eval_file(filename).map_err(|e| {
+        let _ = eval_config_file(&mut ctx, filename).map_err(|e| {
             log::error!("Tulisp error:\n{}", e.format(&ctx));
             e
         });
+        let source = ConfigSource::File(filename.to_string());
+        let sim = Self::with_context(ctx, source, registry, metrics);
+        if let Err(err) = sim.rebuild_registry() {
+            log::error!("Tulisp error:\n{}", err.format(&sim.ctx.borrow()));
+        }
+        sim
+    }
+
+    /// Creates a config from lisp source, instead of a file.  The
+    /// simulator's lisp files and the defaults from `sim/defaults.lisp`
+    /// are preloaded, so the source only has to build the components,
+    /// and doesn't depend on the working directory.
+    pub(crate) fn from_source(
+        source: &str,
+        registry: SharedRegistry,
+        events: Arc<Events>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, String> {
+        let mut ctx = tulisp::TulispContext::new();
+        add_functions(&mut ctx, events);
+
+        for (name, lisp) in SIM_FILES {
+            ctx.eval_string(lisp)
+                .map_err(|e| format!("Tulisp error in {name}:\n{}", e.format(&ctx)))?;
+        }
+        let source = ConfigSource::Lisp(source.into());
+        ctx.eval_string("(setq simulator-loaded t)")
+            .and_then(|_| source.eval(&mut ctx))
+            .map_err(|e| format!("Tulisp error:\n{}", e.format(&ctx)))?;
+        let sim = Self::with_context(ctx, source, registry, metrics);
+        sim.rebuild_registry()
+            .map_err(|e| format!("Tulisp error:\n{}", e.format(&sim.ctx.borrow())))?;
+        Ok(sim)
+    }
+
+    fn with_context(
+        mut ctx: TulispContext,
+        source: ConfigSource,
+        registry: SharedRegistry,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let now = std::time::Instant::now();
         let symbols = Symbols::new(&mut ctx);
         Self {
-            filename: filename.to_string(),
+            source,
             ctx: Rc::new(RefCell::new(ctx)),
             stream_methods: Rc::new(RefCell::new(HashMap::new())),
+            registry,
             last_formula_update_time: Rc::new(RefCell::new(now)),
+            metrics,
             symbols,
         }
     }
 
-    pub fn reload(&self) {
+    /// Compiles the components from the lisp alists, and replaces the
+    /// registry and the stream methods with them, unless a component
+    /// fails to compile.
+    fn rebuild_registry(&self) -> Result<(), Error> {
+        let components = self.components_from(self.symbols.components_alist.get()?)?;
+        let hidden_components =
+            self.components_from(self.symbols.hidden_components_alist.get()?)?;
+        let connections = Self::connections_from(self.symbols.connections_alist.get()?)?;
+        let hidden_connections =
+            Self::connections_from(self.symbols.hidden_connections_alist.get()?)?;
+
+        let mut stream_methods = StreamMethods::new();
+        let mut streams = HashMap::new();
+        for (comp, component) in self
+            .symbols
+            .components_alist
+            .get()?
+            .base_iter()
+            .zip(&components.components)
+        {
+            let mut ctx = self.ctx.borrow_mut();
+            let stream = alist_get_as!(&mut ctx, &comp, &self.symbols.stream)?;
+            if stream.null() {
+                continue;
+            }
+            let interval = alist_get_as!(&mut ctx, &stream, &self.symbols.interval, as_int)?;
+            let data_method = alist_get_as!(&mut ctx, &stream, &self.symbols.data)?;
+            let comm_faults = alist_get_as!(&mut ctx, &stream, &self.symbols.comm_faults)?;
+            let comm_faults = CommFaults::from_alist(&mut ctx, &comm_faults)?;
+            let measurement = alist_get_as!(&mut ctx, &stream, &self.symbols.measurement)?;
+            let measurement = MeasurementModel::from_alist(&mut ctx, &measurement, component.id)?;
+            let conv_function = Self::get_conv_function(component)?;
+
+            stream_methods.insert(
+                component.id,
+                (data_method, interval as u64, conv_function, comm_faults),
+            );
+            streams.insert(
+                component.id,
+                Stream {
+                    interval: interval as u64,
+                    measurement,
+                },
+            );
+        }
+
+        let timeout_categories = self.timeout_categories()?;
+        let mut request_timeouts = HashMap::new();
+        for component in &components.components {
+            if TimeoutCategory::of(component).is_some_and(|cat| timeout_categories.contains(&cat)) {
+                request_timeouts.insert(component.id, self.fallback(component.id)?);
+            }
+        }
+
+        let registry = Registry::new(
+            components,
+            connections,
+            hidden_components,
+            hidden_connections,
+            streams,
+            request_timeouts,
+            self.symbols.single_controller.get().is_ok_and(|x| !x.null()),
+        );
+        *self.stream_methods.borrow_mut() = stream_methods;
+        *self.registry.write().unwrap() = Arc::new(registry);
+        self.symbols.registry_changed.set(TulispObject::nil())?;
+        Ok(())
+    }
+
+    pub(crate) fn reload(&self) {
         let start = std::time::Instant::now();
         let mut ctx = self.ctx.borrow_mut();
-        if ctx
-            .eval_file(&self.filename)
+        if self
+            .source
+            .eval(&mut ctx)
             .map_err(|e| {
                 log::error!("Tulisp error:\n{}", e.format(&ctx));
                 e
             })
             .is_err()
         {
+            self.metrics.reloaded(false);
             return;
         }
+        drop(ctx);
+        if let Err(err) = self.rebuild_registry() {
+            log::error!("Tulisp error:\n{}", err.format(&self.ctx.borrow()));
+            self.metrics.reloaded(false);
+            return;
+        }
+        self.metrics.reloaded(true);
         let duration = start.elapsed();
         log::info!(
             "Reloaded config file in {}ms",
             duration.as_nanos() as f64 / 1e6
         );
-        *self.stream_methods.borrow_mut() = HashMap::new();
     }
 
-    pub async fn start(self) {
+    /// Loads a scenario file, which starts running with the next state
+    /// update.
+    pub(crate) fn load_scenario(&self, filename: &str) -> Result<(), String> {
+        let mut ctx = self.ctx.borrow_mut();
+        ctx.eval_string(SIM_SCENARIO)
+            .and_then(|_| ctx.eval_file(filename))
+            .map_err(|e| format!("Tulisp error:\n{}", e.format(&ctx)))?;
+        if self.symbols.scenario_status.get().unwrap_or_default().null() {
+            return Err(format!("No `scenario' found in {filename}"));
+        }
+        Ok(())
+    }
+
+    /// Returns whether the scenario passed, once it has finished.
+    pub(crate) fn scenario_result(&self) -> Option<bool> {
+        let status = self.symbols.scenario_status.get().unwrap_or_default();
+        if status.null() || status.eq(&self.ctx.borrow_mut().intern("running")) {
+            return None;
+        }
+        Some(status.eq(&self.ctx.borrow_mut().intern("passed")))
+    }
+
+    /// Starts the state updates and watching the config file, on the
+    /// simulation thread.
+    pub(crate) fn start(&self) {
         self.start_state_updates();
-        self.start_watching().await;
+        tokio::task::spawn_local(self.clone().start_watching());
+    }
+
+    /// Formats an error from this simulation's lisp context, so that it
+    /// can be sent to other threads.
+    pub(crate) fn error(&self, err: Error) -> crate::config::SimError {
+        crate::config::SimError::new(err.desc().to_string(), err.format(&self.ctx.borrow()))
+    }
+
+    /// Evaluates lisp code, and returns the printed result.  The
+    /// registry is rebuilt afterwards only if the code set
+    /// `registry-changed`, which adding components does.
+    pub(crate) fn eval(&self, source: &str) -> Result<String, Error> {
+        let res = self.ctx.borrow_mut().eval_string(source)?;
+        if self.symbols.registry_changed.get().is_ok_and(|x| !x.null()) {
+            self.rebuild_registry()?;
+        }
+        Ok(res.to_string())
     }
 
     async fn start_watching(self) {
+        let ConfigSource::File(filename) = &self.source else {
+            return;
+        };
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
 
         let mut watcher = RecommendedWatcher::new(
@@ -257,7 +529,7 @@ impl Config {
         .unwrap();
         watcher
             .watch(
-                &Path::new(&self.filename),
+                &Path::new(filename),
                 notify::RecursiveMode::NonRecursive,
             )
             .unwrap();
@@ -278,9 +550,9 @@ impl Config {
         }
     }
 
-    fn start_state_updates(&self) {
+    pub(crate) fn start_state_updates(&self) -> tokio::task::JoinHandle<()> {
         let config = self.clone();
-        tokio::spawn(async move {
+        tokio::task::spawn_local(async move {
             loop {
                 config.update_state();
                 let update_interval = config
@@ -291,38 +563,77 @@ impl Config {
                     .unwrap_or(2000) as u64;
                 tokio::time::sleep(Duration::from_millis(update_interval)).await;
             }
-        });
+        })
     }
 
     fn update_state(&self) {
-        let exprs_alist = self
-            .symbols
-            .state_update_functions
-            .get()
-            .map_err(|e| {
-                log::error!("Tulisp error:\n{}", e.format(&self.ctx.borrow()));
-                panic!("Update state function failed");
-            })
-            .unwrap();
-        let last_update_time = self.last_formula_update_time.borrow();
         let now = std::time::Instant::now();
-
-        for func in exprs_alist.base_iter() {
-            let res = self.ctx.borrow_mut().funcall(
-                &func,
-                &list![(now.duration_since(*last_update_time).as_millis() as i64).into()].unwrap(),
+        let elapsed_ms = now
+            .duration_since(*self.last_formula_update_time.borrow())
+            .as_millis() as i64;
+
+        // A failing state update function shouldn't stop the
+        // simulation, which keeps ticking until the config is fixed.
+        if let Err(e) = self.advance(elapsed_ms) {
+            log::error!(
+                "Update state failed with Tulisp error:\n{}",
+                e.format(&self.ctx.borrow())
             );
-            res.map_err(|e| {
-                log::error!("Tulisp error:\n{}", e.format(&self.ctx.borrow()));
-                panic!("Update state function failed");
-            })
-            .unwrap();
         }
-        drop(last_update_time);
         *self.last_formula_update_time.borrow_mut() = now;
+
+        self.metrics.observe_update_state(now.elapsed());
+    }
+
+    /// Advances the simulation by `elapsed`, for when time is stepped
+    /// manually, instead of with `start`.
+    pub(crate) fn step(&self, elapsed: Duration) -> Result<(), Error> {
+        self.advance(elapsed.as_millis() as i64)
     }
 
-    pub fn socket_addr(&self) -> String {
+    fn advance(&self, elapsed_ms: i64) -> Result<(), Error> {
+        let time_ms = self.symbols.simulation_time_ms.get()?.as_int()?;
+        self.symbols
+            .simulation_time_ms
+            .set((time_ms + elapsed_ms).into())?;
+
+        let exprs_alist = self.symbols.state_update_functions.get()?;
+        for func in exprs_alist.base_iter() {
+            self.ctx
+                .borrow_mut()
+                .funcall(&func, &list![elapsed_ms.into()]?)?;
+        }
+
+        self.update_scenario(elapsed_ms);
+
+        // Reload when the lisp code asked for it, whether time advances
+        // on its own or is stepped manually.
+        if self.symbols.reload_requested.get().is_ok_and(|x| !x.null()) {
+            self.symbols.reload_requested.set(TulispObject::nil())?;
+            self.reload();
+        }
+        Ok(())
+    }
+
+    /// Advances the running scenario, if any.  Errors in scenario
+    /// actions or checks fail the scenario.
+    fn update_scenario(&self, elapsed_ms: i64) {
+        let status = self.symbols.scenario_status.get().unwrap_or_default();
+        if !status.eq(&self.ctx.borrow_mut().intern("running")) {
+            return;
+        }
+        let res = self
+            .ctx
+            .borrow_mut()
+            .funcall(&self.symbols.scenario_tick, &list![elapsed_ms.into()].unwrap());
+        if let Err(e) = res {
+            log::error!("Scenario failed with Tulisp error:\n{}", e.format(&self.ctx.borrow()));
+            let failed = self.ctx.borrow_mut().intern("failed");
+            let _ = self.symbols.scenario_status.set(failed);
+        }
+    }
+
+    pub(crate) fn socket_addr(&self) -> String {
         let addr = self.symbols.socket_addr.get().and_then(|x| x.as_string());
 
         match addr {
@@ -340,7 +651,20 @@ Invalid socket-addr.  Add a config line in this format:
         }
     }
 
-    pub fn retain_requests_duration(&self) -> Duration {
+    /// The simulated time since the simulation started, from
+    /// `simulation-time-ms`.
+    pub(crate) fn simulation_time(&self) -> Duration {
+        let time_ms = self
+            .symbols
+            .simulation_time_ms
+            .get()
+            .and_then(|x| x.as_int())
+            .unwrap_or_default();
+
+        Duration::from_millis(time_ms.max(0) as u64)
+    }
+
+    pub(crate) fn retain_requests_duration(&self) -> Duration {
         let dur_ms = self
             .symbols
             .retain_requests_duration_ms
@@ -351,7 +675,40 @@ Invalid socket-addr.  Add a config line in this format:
         Duration::from_millis(dur_ms as u64)
     }
 
-    pub fn metadata(&self) -> Result<MicrogridMetadata, Error> {
+    /// The kinds of components whose power requests expire, from
+    /// `request-timeout-categories`.  Only battery inverters, when it
+    /// isn't set.
+    fn timeout_categories(&self) -> Result<Vec<TimeoutCategory>, Error> {
+        let Ok(categories) = self.symbols.request_timeout_categories.get() else {
+            return Ok(vec![TimeoutCategory::BatteryInverter]);
+        };
+        categories
+            .base_iter()
+            .map(|x| {
+                x.as_symbol()?
+                    .parse::<TimeoutCategory>()
+                    .map_err(|e| Error::new(ErrorKind::TypeMismatch, e))
+            })
+            .collect()
+    }
+
+    /// What happens to a component when its power request expires, from
+    /// `request-timeout-fallback-for`.
+    fn fallback(&self, component_id: u64) -> Result<Fallback, Error> {
+        let fallback = self.ctx.borrow_mut().funcall(
+            &self.symbols.request_timeout_fallback_for,
+            &list![(component_id as i64).into()]?,
+        )?;
+        if fallback.numberp() {
+            return Ok(Fallback::Setpoint(fallback.try_float()? as f32));
+        }
+        fallback
+            .as_symbol()?
+            .parse::<Fallback>()
+            .map_err(|e| Error::new(ErrorKind::TypeMismatch, e))
+    }
+
+    pub(crate) fn metadata(&self) -> Result<MicrogridMetadata, Error> {
         let alist = self.symbols.metadata.get().unwrap_or_else(|_|TulispObject::nil());
 
         let microgrid_id = alist_get_as!(
@@ -382,38 +739,42 @@ Invalid socket-addr.  Add a config line in this format:
         })
     }
 
-    pub fn components(&self) -> Result<ComponentList, Error> {
-        let alists = self.symbols.components_alist.get()?;
+    fn components_from(&self, alists: TulispObject) -> Result<ComponentList, Error> {
         Ok(ComponentList {
             components: alists
                 .base_iter()
-                .map(|x| {
-                    make_component_from_alist(&mut self.ctx.borrow_mut(), &x, &self.symbols)
-                        .unwrap()
-                })
-                .collect(),
+                .map(|x| make_component_from_alist(&mut self.ctx.borrow_mut(), &x, &self.symbols))
+                .collect::<Result<_, Error>>()?,
         })
     }
 
-    pub fn connections(&self) -> Result<ConnectionList, Error> {
-        let alist = self.symbols.connections_alist.get()?;
+    fn connections_from(alist: TulispObject) -> Result<ConnectionList, Error> {
         Ok(ConnectionList {
             connections: alist
                 .base_iter()
-                .map(|x| Connection {
-                    start: x.car().and_then(|x| x.as_int()).unwrap() as u64,
-                    end: x.cdr().and_then(|x| x.as_int()).unwrap() as u64,
-                    ..Default::default()
+                .map(|x| {
+                    Ok(Connection {
+                        start: x.car().and_then(|x| x.as_int())? as u64,
+                        end: x.cdr().and_then(|x| x.as_int())? as u64,
+                        ..Default::default()
+                    })
                 })
-                .collect(),
+                .collect::<Result<_, Error>>()?,
         })
     }
 
-    pub fn set_power_active(&self, component_id: u64, power: f32) -> Result<(), Error> {
-        let res = self.ctx.borrow_mut().funcall(
-            &self.symbols.set_power_active,
-            &list![(component_id as i64).into(), (power as f64).into()]?,
-        )?;
+    pub(crate) fn rpc_faults(
+        &self,
+        method: &str,
+        component_id: Option<u64>,
+    ) -> Result<RpcFaults, Error> {
+        RpcFaults::from_config(&mut self.ctx.borrow_mut(), method, component_id)
+    }
+
+    /// Calls a lisp command function, which returns `nil` on success,
+    /// or an error message.
+    fn run_command(&self, func: &TulispObject, args: &TulispObject) -> Result<(), Error> {
+        let res = self.ctx.borrow_mut().funcall(func, args)?;
 
         if !res.null() {
             return Err(Error::new(tulisp::ErrorKind::Undefined, res.as_string()?).with_trace(res));
@@ -421,88 +782,89 @@ Invalid socket-addr.  Add a config line in this format:
         Ok(())
     }
 
-    fn get_conv_function(&self, component_id: u64, comp: &TulispObject) -> CompDataMaker {
-        match make_component_from_alist(&mut self.ctx.borrow_mut(), &comp, &self.symbols)
-            .unwrap()
-            .category()
-        {
-            ComponentCategory::Battery => Self::battery_data,
-            ComponentCategory::Inverter => Self::inverter_data,
-            ComponentCategory::Meter => Self::meter_data,
-            ComponentCategory::EvCharger => Self::ev_charger_data,
-            _ => Err(Error::new(
-                tulisp::ErrorKind::Uninitialized,
-                format!("Invalid component category for component {}", component_id),
-            ))
-            .unwrap(),
-        }
+    pub(crate) fn set_power_active(&self, component_id: u64, power: f32) -> Result<(), Error> {
+        self.run_command(
+            &self.symbols.set_power_active,
+            &list![(component_id as i64).into(), (power as f64).into()]?,
+        )
     }
 
-    pub fn get_component_data(&self, component_id: u64) -> Result<(ComponentData, u64), Error> {
-        let mut stream_methods = self.stream_methods.borrow_mut();
-        let (data_method, interval, conv_function) =
-            if let Some((data_method, interval, conv_function)) = stream_methods.get(&component_id)
-            {
-                (data_method.clone(), *interval, *conv_function)
-            } else {
-                let alists = self.symbols.components_alist.get()?;
-                let comp = alists
-                    .base_iter()
-                    .find(|x| {
-                        alist_get_as!(&mut self.ctx.borrow_mut(), &x, &self.symbols.id, as_int)
-                            .unwrap() as u64
-                            == component_id
-                    })
-                    .expect(&format!("Component id {component_id} not found"));
-
-                let stream =
-                    alist_get_as!(&mut self.ctx.borrow_mut(), &comp, &self.symbols.stream).unwrap();
-
-                let interval = alist_get_as!(
-                    &mut self.ctx.borrow_mut(),
-                    &stream,
-                    &self.symbols.interval,
-                    as_int
-                )
-                .unwrap();
-                let data_method =
-                    alist_get_as!(&mut self.ctx.borrow_mut(), &stream, &self.symbols.data).unwrap();
+    pub(crate) fn start_component(&self, component_id: u64) -> Result<(), Error> {
+        self.run_command(
+            &self.symbols.start_component,
+            &list![(component_id as i64).into()]?,
+        )
+    }
 
-                let conv_function = self.get_conv_function(component_id, &comp);
+    pub(crate) fn standby_component(&self, component_id: u64) -> Result<(), Error> {
+        self.run_command(
+            &self.symbols.standby_component,
+            &list![(component_id as i64).into()]?,
+        )
+    }
 
-                stream_methods.insert(
-                    component_id,
-                    (data_method.clone(), interval as u64, conv_function),
-                );
+    pub(crate) fn stop_component(&self, component_id: u64) -> Result<(), Error> {
+        self.run_command(
+            &self.symbols.stop_component,
+            &list![(component_id as i64).into()]?,
+        )
+    }
 
-                (data_method, interval as u64, conv_function)
-            };
+    fn get_conv_function(component: &Component) -> Result<CompDataMaker, Error> {
+        match component.category() {
+            ComponentCategory::Battery => Ok(Self::battery_data),
+            ComponentCategory::Inverter => Ok(Self::inverter_data),
+            ComponentCategory::Meter => Ok(Self::meter_data),
+            ComponentCategory::EvCharger => Ok(Self::ev_charger_data),
+            _ => Err(Error::new(
+                tulisp::ErrorKind::Uninitialized,
+                format!("Invalid component category for component {}", component.id),
+            )),
+        }
+    }
 
-        let tulisp_data = self
-            .ctx
-            .borrow_mut()
-            .funcall(&data_method, &list!((component_id as i64).into())?);
-        let tulisp_data = tulisp_data.map_err(|e| {
-            log::error!("Tulisp error:\n{}", e.format(&self.ctx.borrow()));
-            panic!();
-        })?;
+    pub(crate) fn get_component_data(
+        &self,
+        component_id: u64,
+    ) -> Result<(ComponentData, u64, CommFaults), tonic::Status> {
+        let Some((data_method, interval, conv_function, comm_faults)) =
+            self.stream_methods.borrow().get(&component_id).cloned()
+        else {
+            return Err(tonic::Status::not_found(format!(
+                "Component id {component_id} not found, or has no data stream"
+            )));
+        };
 
-        let comp_data = conv_function(&mut self.ctx.borrow_mut(), &tulisp_data, &self.symbols);
-        let comp_data = comp_data.map_err(|e| {
+        let internal = |e: Error| {
             log::error!("Tulisp error:\n{}", e.format(&self.ctx.borrow()));
-            panic!();
-        })?;
+            tonic::Status::internal(e.desc())
+        };
+        let args = list!((component_id as i64).into()).map_err(internal)?;
+        // The context is borrowed until the end of each call, so errors
+        // are formatted only after it.
+        let tulisp_data = self.ctx.borrow_mut().funcall(&data_method, &args);
+        let tulisp_data = tulisp_data.map_err(internal)?;
+
+        let registry = self.registry.read().unwrap().clone();
+        let comp_data = conv_function(
+            &mut self.ctx.borrow_mut(),
+            &tulisp_data,
+            &self.symbols,
+            registry.measurement(component_id),
+        );
+        let comp_data = comp_data.map_err(internal)?;
 
-        Ok((comp_data, interval as u64))
+        Ok((comp_data, interval, comm_faults))
     }
 }
 
 /// ComponentData methods
-impl Config {
+impl Simulation {
     fn battery_data(
         ctx: &mut TulispContext,
         alist: &TulispObject,
         symbols: &Symbols,
+        _measurement: Option<&MeasurementModel>,
     ) -> Result<ComponentData, Error> {
         let id = alist_get_as!(ctx, &alist, &symbols.id, eval ++ as_int)? as u64;
         let capacity = alist_get_f32!(ctx, &alist, &symbols.capacity);
@@ -582,28 +944,77 @@ impl Config {
         ctx: &mut TulispContext,
         alist: &TulispObject,
         symbols: &Symbols,
+        model: Option<&MeasurementModel>,
     ) -> Result<Ac, Error> {
+        let measure = |quantity: Quantity, value: f32| match model {
+            Some(model) => model.measure(quantity, value),
+            None => value,
+        };
+        let measure_3_phase = |quantity: Quantity, (l1, l2, l3): (f32, f32, f32)| {
+            (
+                measure(quantity, l1),
+                measure(quantity, l2),
+                measure(quantity, l3),
+            )
+        };
+
         let frequency = symbols
             .ac_frequency
             .get()
             .and_then(|x| x.as_float())
             .unwrap_or_default() as f32;
-        let current = alist_get_3_phase!(ctx, &alist, &symbols.current);
-        let voltage = alist_get_3_phase!(ctx, &alist, &symbols.voltage);
-        let per_phase_power = alist_get_3_phase!(ctx, &alist, &symbols.per_phase_power);
+        let frequency = measure(Quantity::Frequency, frequency);
+        let current = measure_3_phase(
+            Quantity::Current,
+            alist_get_3_phase!(ctx, &alist, &symbols.current),
+        );
+        let voltage = measure_3_phase(
+            Quantity::Voltage,
+            alist_get_3_phase!(ctx, &alist, &symbols.voltage),
+        );
+        let per_phase_power = measure_3_phase(
+            Quantity::Power,
+            alist_get_3_phase!(ctx, &alist, &symbols.per_phase_power),
+        );
 
-        let power = alist_get_f32!(ctx, &alist, &symbols.power);
+        let power = measure(Quantity::Power, alist_get_f32!(ctx, &alist, &symbols.power));
 
         let inclusion_lower = alist_get_f32!(ctx, &alist, &symbols.inclusion_lower);
         let inclusion_upper = alist_get_f32!(ctx, &alist, &symbols.inclusion_upper);
         let exclusion_lower = alist_get_f32!(ctx, &alist, &symbols.exclusion_lower);
         let exclusion_upper = alist_get_f32!(ctx, &alist, &symbols.exclusion_upper);
 
+        // Only meters and inverters have energy counters.
+        let energy_active = if alist_get_as!(ctx, &alist, &symbols.energy_consumed)?.null() {
+            None
+        } else {
+            let consumed = alist_get_f32!(ctx, &alist, &symbols.energy_consumed);
+            let delivered = alist_get_f32!(ctx, &alist, &symbols.energy_delivered);
+            Some(Energy {
+                energy: Some(Metric {
+                    value: consumed - delivered,
+                    ..Default::default()
+                }),
+                energy_consumed: Some(Metric {
+                    value: consumed,
+                    ..Default::default()
+                }),
+                energy_delivered: Some(Metric {
+                    value: delivered,
+                    ..Default::default()
+                }),
+            })
+        };
+
         Ok(Ac {
             frequency: Some(Metric {
                 value: frequency,
                 ..Default::default()
             }),
+            energy_active,
+            // The total current is the sum of the phase currents, so
+            // that it is the current of the only phase of single-phase
+            // components.
             current: Some(Metric {
                 value: current.0 + current.1 + current.2,
                 ..Default::default()
@@ -673,6 +1084,7 @@ impl Config {
         ctx: &mut TulispContext,
         alist: &TulispObject,
         symbols: &Symbols,
+        measurement: Option<&MeasurementModel>,
     ) -> Result<ComponentData, Error> {
         let id = alist_get_as!(ctx, &alist, &symbols.id, eval ++ as_int)? as u64;
 
@@ -690,7 +1102,7 @@ impl Config {
             data: Some(component_data::Data::Inverter(inverter::Inverter {
                 state: Some(inverter::State { component_state }),
                 data: Some(inverter::Data {
-                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols)?),
+                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols, measurement)?),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -702,6 +1114,7 @@ impl Config {
         ctx: &mut TulispContext,
         alist: &TulispObject,
         symbols: &Symbols,
+        measurement: Option<&MeasurementModel>,
     ) -> Result<ComponentData, Error> {
         let id = alist_get_as!(ctx, &alist, &symbols.id, eval ++ as_int)? as u64;
 
@@ -719,7 +1132,7 @@ impl Config {
                     .unwrap_or_default() as i32,
                 }),
                 data: Some(meter::Data {
-                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols)?),
+                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols, measurement)?),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -731,6 +1144,7 @@ impl Config {
         ctx: &mut TulispContext,
         alist: &TulispObject,
         symbols: &Symbols,
+        measurement: Option<&MeasurementModel>,
     ) -> Result<ComponentData, Error> {
         let id = alist_get_as!(ctx, &alist, &symbols.id, eval ++ as_int)? as u64;
 
@@ -755,7 +1169,7 @@ impl Config {
                     cable_state,
                 }),
                 data: Some(ev_charger::Data {
-                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols)?),
+                    ac: Some(Self::ac_from_alist(ctx, &alist, symbols, measurement)?),
                     ..Default::default()
                 }),
                 ..Default::default()
@@ -764,7 +1178,7 @@ impl Config {
     }
 }
 
-fn add_functions(ctx: &mut TulispContext) {
+fn add_functions(ctx: &mut TulispContext, events: Arc<Events>) {
     macro_rules! log_impl {
         ($level:ident) => {
             |ctx, args| {
@@ -791,4 +1205,15 @@ fn add_functions(ctx: &mut TulispContext) {
         };
         Ok(rnd.into())
     });
+
+    ctx.add_special_form("unix-time", |_ctx, _args| {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(now.as_secs_f64().into())
+    });
+
+    crate::profile::add_functions(ctx);
+    crate::grid_conditions::add_functions(ctx);
+    crate::events::add_functions(ctx, events);
 }
@@ -0,0 +1,265 @@
+use std::{cell::RefCell, collections::HashMap, f64::consts::TAU};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tulisp::{Error, TulispContext, TulispObject};
+
+use crate::alist::{get, get_float};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Quantity {
+    Power,
+    Current,
+    Voltage,
+    Frequency,
+}
+
+impl Quantity {
+    const ALL: [Quantity; 4] = [
+        Quantity::Power,
+        Quantity::Current,
+        Quantity::Voltage,
+        Quantity::Frequency,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Quantity::Power => "power",
+            Quantity::Current => "current",
+            Quantity::Voltage => "voltage",
+            Quantity::Frequency => "frequency",
+        }
+    }
+}
+
+#[derive(Default)]
+struct MetricModel {
+    /// Standard deviation of the gaussian noise, in the metric's unit.
+    noise: f64,
+    /// Constant offset, in the metric's unit.
+    offset: f64,
+    /// Maximum gain error in percent.  Each sensor gets a fixed gain
+    /// error within this range.
+    accuracy_class: f64,
+    /// Resolution of the reported values.
+    quantization: f64,
+    outlier_rate: f64,
+    outlier_factor: f64,
+}
+
+/// The random state of a component's sensors, which persists across
+/// samples, so that seeded models are reproducible.
+struct Sensor {
+    seed: Option<u64>,
+    rng: StdRng,
+    gain_errors: HashMap<Quantity, f64>,
+}
+
+impl Sensor {
+    fn new(seed: Option<u64>, component_id: u64) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed ^ component_id),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            seed,
+            rng,
+            gain_errors: HashMap::new(),
+        }
+    }
+}
+
+thread_local! {
+    static SENSORS: RefCell<HashMap<u64, Sensor>> = RefCell::new(HashMap::new());
+}
+
+/// How a component measures the values it reports.
+///
+/// Configured with the `measurement` key in a component's config, as
+/// an alist with an optional `seed`, and per-metric alists for `power`,
+/// `current`, `voltage` and `frequency`, with these optional keys:
+///
+///   - `noise`: standard deviation of gaussian noise.
+///   - `offset`: constant bias added to readings.
+///   - `accuracy-class`: maximum gain error in percent of the reading.
+///   - `quantization`: resolution of the readings.
+///   - `outlier-rate`: probability of a reading being an outlier.
+///   - `outlier-factor`: how much outliers are scaled by, default 10.
+pub(crate) struct MeasurementModel {
+    component_id: u64,
+    seed: Option<u64>,
+    metrics: Vec<(Quantity, MetricModel)>,
+}
+
+impl MeasurementModel {
+    pub(crate) fn from_alist(
+        ctx: &mut TulispContext,
+        alist: &TulispObject,
+        component_id: u64,
+    ) -> Result<Option<Self>, Error> {
+        if alist.null() {
+            return Ok(None);
+        }
+        let seed = get_float(ctx, alist, "seed")?.map(|x| x as u64);
+
+        let mut metrics = Vec::new();
+        for quantity in Quantity::ALL {
+            let metric = get(ctx, alist, quantity.name())?;
+            if metric.null() {
+                continue;
+            }
+            let get_or_zero = |ctx: &mut TulispContext, key| {
+                get_float(ctx, &metric, key).map(|x| x.unwrap_or_default())
+            };
+            metrics.push((
+                quantity,
+                MetricModel {
+                    noise: get_or_zero(ctx, "noise")?,
+                    offset: get_or_zero(ctx, "offset")?,
+                    accuracy_class: get_or_zero(ctx, "accuracy-class")?,
+                    quantization: get_or_zero(ctx, "quantization")?,
+                    outlier_rate: get_or_zero(ctx, "outlier-rate")?,
+                    outlier_factor: get_float(ctx, &metric, "outlier-factor")?.unwrap_or(10.0),
+                },
+            ));
+        }
+
+        Ok(Some(Self {
+            component_id,
+            seed,
+            metrics,
+        }))
+    }
+
+    /// Returns the reading of the given quantity, for its actual
+    /// `value`.
+    pub(crate) fn measure(&self, quantity: Quantity, value: f32) -> f32 {
+        let Some((_, model)) = self.metrics.iter().find(|(q, _)| *q == quantity) else {
+            return value;
+        };
+
+        SENSORS.with_borrow_mut(|sensors| {
+            let sensor = sensors
+                .entry(self.component_id)
+                .or_insert_with(|| Sensor::new(self.seed, self.component_id));
+            if sensor.seed != self.seed {
+                *sensor = Sensor::new(self.seed, self.component_id);
+            }
+            let rng = &mut sensor.rng;
+
+            let gain_error = *sensor
+                .gain_errors
+                .entry(quantity)
+                .or_insert_with(|| rng.gen_range(-1.0..=1.0) * model.accuracy_class / 100.0);
+
+            let mut reading = value as f64 * (1.0 + gain_error) + model.offset;
+            if model.noise > 0.0 {
+                // Box-Muller transform, for a standard normal sample.
+                let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+                let u2: f64 = rng.gen();
+                reading += model.noise * (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos();
+            }
+            if model.outlier_rate > 0.0 && rng.gen_bool(model.outlier_rate.min(1.0)) {
+                reading *= model.outlier_factor;
+            }
+            if model.quantization > 0.0 {
+                reading = (reading / model.quantization).round() * model.quantization;
+            }
+            reading as f32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn power_model(component_id: u64, seed: u64, metric: MetricModel) -> MeasurementModel {
+        MeasurementModel {
+            component_id,
+            seed: Some(seed),
+            metrics: vec![(Quantity::Power, metric)],
+        }
+    }
+
+    #[test]
+    fn quantizes_readings() {
+        let model = power_model(
+            1,
+            42,
+            MetricModel {
+                quantization: 0.5,
+                ..Default::default()
+            },
+        );
+        assert_eq!(model.measure(Quantity::Power, 1.26), 1.5);
+        assert_eq!(model.measure(Quantity::Power, -3.1), -3.0);
+        // Quantities without a model are reported as they are.
+        assert_eq!(model.measure(Quantity::Voltage, 1.26), 1.26);
+    }
+
+    #[test]
+    fn keeps_gain_errors_within_the_accuracy_class() {
+        let mut errors = Vec::new();
+        for id in 1..=50 {
+            let model = power_model(
+                id,
+                42,
+                MetricModel {
+                    accuracy_class: 2.0,
+                    ..Default::default()
+                },
+            );
+            let reading = model.measure(Quantity::Power, 1000.0);
+            assert!((980.0..=1020.0).contains(&reading), "{id}: {reading}");
+            // Each sensor keeps its gain error.
+            assert_eq!(model.measure(Quantity::Power, 1000.0), reading);
+            errors.push(reading - 1000.0);
+        }
+        assert!(errors.iter().any(|e| *e > 1.0) && errors.iter().any(|e| *e < -1.0));
+    }
+
+    #[test]
+    fn adds_gaussian_noise() {
+        let model = power_model(
+            1,
+            42,
+            MetricModel {
+                noise: 10.0,
+                ..Default::default()
+            },
+        );
+        let readings = (0..1000)
+            .map(|_| model.measure(Quantity::Power, 1000.0) as f64)
+            .collect::<Vec<_>>();
+        let mean = readings.iter().sum::<f64>() / readings.len() as f64;
+        let std_dev = (readings.iter().map(|r| (r - mean).powi(2)).sum::<f64>()
+            / readings.len() as f64)
+            .sqrt();
+        assert!((mean - 1000.0).abs() < 2.0, "{mean}");
+        assert!((9.0..11.0).contains(&std_dev), "{std_dev}");
+    }
+
+    #[test]
+    fn reproduces_seeded_readings() {
+        let noise = || MetricModel {
+            noise: 10.0,
+            ..Default::default()
+        };
+        let model = power_model(1, 7, noise());
+        let first = (0..5)
+            .map(|_| model.measure(Quantity::Power, 1000.0))
+            .collect::<Vec<_>>();
+
+        // A different seed restarts the sensor, and the first seed
+        // repeats its readings after that.
+        let other = power_model(1, 8, noise());
+        let second = (0..5)
+            .map(|_| other.measure(Quantity::Power, 1000.0))
+            .collect::<Vec<_>>();
+        assert_ne!(first, second);
+        let again = (0..5)
+            .map(|_| model.measure(Quantity::Power, 1000.0))
+            .collect::<Vec<_>>();
+        assert_eq!(first, again);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, routing::get, Router};
+
+use crate::{
+    config::Config,
+    proto::microgrid::{component_data, ComponentData},
+    registry::Registry,
+};
+
+/// The quantiles of the exported summaries.
+const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// How many recent observations the quantiles are calculated from.
+const MAX_OBSERVATIONS: usize = 1000;
+
+#[derive(Default)]
+struct Timing {
+    count: u64,
+    sum: f64,
+    /// The most recent observations, in seconds.
+    recent: VecDeque<f64>,
+}
+
+impl Timing {
+    fn observe(&mut self, duration: Duration) {
+        self.count += 1;
+        self.sum += duration.as_secs_f64();
+        if self.recent.len() == MAX_OBSERVATIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(duration.as_secs_f64());
+    }
+
+    /// Writes the lines of a summary, with the quantiles of the recent
+    /// observations.  `labels` are prepended to the quantile label.
+    fn render(&self, out: &mut String, name: &str, labels: &str) -> std::fmt::Result {
+        let mut sorted: Vec<f64> = self.recent.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let sep = if labels.is_empty() { "" } else { "," };
+        for quantile in QUANTILES {
+            let value = match sorted.len() {
+                0 => f64::NAN,
+                len => sorted[((len - 1) as f64 * quantile).round() as usize],
+            };
+            writeln!(
+                out,
+                "{name}{{{labels}{sep}quantile=\"{quantile}\"}} {value}"
+            )?;
+        }
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        writeln!(out, "{name}_sum{labels} {}", self.sum)?;
+        writeln!(out, "{name}_count{labels} {}", self.count)
+    }
+}
+
+/// Counters of the simulator's internals, exported with the current
+/// component values on the `/metrics` endpoint.  Each simulation has
+/// its own, from `Config::metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    update_state: Mutex<Timing>,
+    active_streams: AtomicI64,
+    streams: AtomicU64,
+    /// (method, status code) -> latency
+    rpcs: Mutex<BTreeMap<(String, String), Timing>>,
+    reloads: AtomicU64,
+    reload_errors: AtomicU64,
+    /// Component ID -> time of the last command
+    commands: Mutex<HashMap<u64, Instant>>,
+    /// Component ID -> the data last sent to a client
+    sent: Mutex<BTreeMap<u64, ComponentData>>,
+}
+
+impl Metrics {
+    pub(crate) fn observe_update_state(&self, duration: Duration) {
+        self.update_state.lock().unwrap().observe(duration);
+    }
+
+    pub(crate) fn observe_rpc(&self, method: &str, code: tonic::Code, duration: Duration) {
+        self.rpcs
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), format!("{code:?}")))
+            .or_default()
+            .observe(duration);
+    }
+
+    pub(crate) fn observe_command(&self, component_id: u64) {
+        self.commands
+            .lock()
+            .unwrap()
+            .insert(component_id, Instant::now());
+    }
+
+    pub(crate) fn last_commands(&self) -> HashMap<u64, Instant> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub(crate) fn stream_started(&self) {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Keeps the data sent to a client, for exporting the component
+    /// values without sampling the components again.
+    pub(crate) fn observe_sent(&self, data: &ComponentData) {
+        self.sent.lock().unwrap().insert(data.id, data.clone());
+    }
+
+    pub(crate) fn stream_ended(&self) {
+        self.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reloaded(&self, ok: bool) {
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.reload_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn render(&self, out: &mut String) -> std::fmt::Result {
+        let update_state = self.update_state.lock().unwrap();
+        writeln!(
+            out,
+            "# HELP microsim_update_state_seconds Time spent in lisp state updates."
+        )?;
+        writeln!(out, "# TYPE microsim_update_state_seconds summary")?;
+        update_state.render(out, "microsim_update_state_seconds", "")?;
+        drop(update_state);
+
+        writeln!(
+            out,
+            "# HELP microsim_streams_active Open component data streams."
+        )?;
+        writeln!(out, "# TYPE microsim_streams_active gauge")?;
+        writeln!(
+            out,
+            "microsim_streams_active {}",
+            self.active_streams.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            out,
+            "# HELP microsim_streams_total Component data streams opened."
+        )?;
+        writeln!(out, "# TYPE microsim_streams_total counter")?;
+        writeln!(
+            out,
+            "microsim_streams_total {}",
+            self.streams.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(out, "# HELP microsim_rpc_seconds Latency of unary RPCs.")?;
+        writeln!(out, "# TYPE microsim_rpc_seconds summary")?;
+        for ((method, code), timing) in self.rpcs.lock().unwrap().iter() {
+            let labels = format!("method=\"{method}\",code=\"{code}\"");
+            timing.render(out, "microsim_rpc_seconds", &labels)?;
+        }
+
+        writeln!(out, "# HELP microsim_reloads_total Config reloads.")?;
+        writeln!(out, "# TYPE microsim_reloads_total counter")?;
+        writeln!(
+            out,
+            "microsim_reloads_total {}",
+            self.reloads.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            out,
+            "# HELP microsim_reload_errors_total Failed config reloads."
+        )?;
+        writeln!(out, "# TYPE microsim_reload_errors_total counter")?;
+        writeln!(
+            out,
+            "microsim_reload_errors_total {}",
+            self.reload_errors.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// Returns the category and the main values of a component's data.
+pub(crate) fn component_values(data: &ComponentData) -> (&'static str, Vec<(&'static str, f32)>) {
+    use component_data::Data;
+
+    let mut values = Vec::new();
+    let mut add_ac = |ac: Option<&crate::proto::common::metrics::electrical::Ac>| {
+        let Some(power) = ac.and_then(|ac| ac.power_active.as_ref()) else {
+            return;
+        };
+        values.push(("power", power.value));
+        if let Some(bounds) = &power.system_inclusion_bounds {
+            values.push(("inclusion_lower", bounds.lower));
+            values.push(("inclusion_upper", bounds.upper));
+        }
+    };
+    let category = match &data.data {
+        Some(Data::Meter(meter)) => {
+            add_ac(meter.data.as_ref().and_then(|d| d.ac.as_ref()));
+            "meter"
+        }
+        Some(Data::Inverter(inverter)) => {
+            add_ac(inverter.data.as_ref().and_then(|d| d.ac.as_ref()));
+            "inverter"
+        }
+        Some(Data::EvCharger(ev_charger)) => {
+            add_ac(ev_charger.data.as_ref().and_then(|d| d.ac.as_ref()));
+            "ev-charger"
+        }
+        Some(Data::Battery(battery)) => {
+            let data = battery.data.as_ref();
+            if let Some(power) = data
+                .and_then(|d| d.dc.as_ref())
+                .and_then(|dc| dc.power.as_ref())
+            {
+                values.push(("power", power.value));
+                if let Some(bounds) = &power.system_inclusion_bounds {
+                    values.push(("inclusion_lower", bounds.lower));
+                    values.push(("inclusion_upper", bounds.upper));
+                }
+            }
+            if let Some(soc) = data.and_then(|d| d.soc.as_ref()) {
+                values.push(("soc", soc.avg));
+            }
+            "battery"
+        }
+        _ => "unknown",
+    };
+    (category, values)
+}
+
+/// Writes the values last sent to clients, of the components that are
+/// still in the `registry`.
+fn render_components(metrics: &Metrics, registry: &Registry, out: &mut String) -> std::fmt::Result {
+    let mut lines: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (id, data) in metrics.sent.lock().unwrap().iter() {
+        if registry.stream_interval(*id).is_none() {
+            continue;
+        }
+        let (category, values) = component_values(data);
+        for (name, value) in values {
+            lines.entry(name).or_default().push(format!(
+                "microsim_component_{name}{{component_id=\"{id}\",category=\"{category}\"}} {value}"
+            ));
+        }
+    }
+
+    for (name, lines) in lines {
+        writeln!(out, "# TYPE microsim_component_{name} gauge")?;
+        for line in lines {
+            writeln!(out, "{line}")?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_metrics(State(config): State<Config>) -> String {
+    let metrics = config.metrics();
+    let mut out = String::new();
+    let _ = metrics.render(&mut out);
+    let _ = render_components(&metrics, &config.registry(), &mut out);
+    out
+}
+
+/// Serves the `/metrics` endpoint in the Prometheus text format.
+pub(crate) async fn serve(config: Config, addr: String) {
+    let app = Router::new()
+        .route("/metrics", get(handle_metrics))
+        .with_state(config);
+
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Unable to serve metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    if let Err(err) = axum::serve(listener, app).await {
+        log::error!("Metrics server failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{
+        common::metrics::{electrical::Ac, Metric},
+        microgrid::meter,
+    };
+
+    fn meter_data(id: u64, power: f32) -> ComponentData {
+        ComponentData {
+            id,
+            data: Some(component_data::Data::Meter(meter::Meter {
+                data: Some(meter::Data {
+                    ac: Some(Ac {
+                        power_active: Some(Metric {
+                            value: power,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_counters_summaries_and_sent_values() {
+        let config = Config::from_source("(make-grid :id 1 :successors (list (make-meter :id 2)))")
+            .await
+            .unwrap();
+        let metrics = config.metrics();
+        for millis in [500, 250, 1000] {
+            metrics.observe_rpc(
+                "set-power-active",
+                tonic::Code::Ok,
+                Duration::from_millis(millis),
+            );
+        }
+        metrics.stream_started();
+        metrics.observe_sent(&meter_data(2, 1500.0));
+        // Not in the registry any more.
+        metrics.observe_sent(&meter_data(99, 1.0));
+
+        let out = handle_metrics(State(config.clone())).await;
+        let lines: Vec<_> = out.lines().collect();
+        let labels = r#"method="set-power-active",code="Ok""#;
+        for expected in [
+            format!(r#"microsim_rpc_seconds{{{labels},quantile="0.5"}} 0.5"#),
+            format!(r#"microsim_rpc_seconds{{{labels},quantile="0.9"}} 1"#),
+            format!(r#"microsim_rpc_seconds{{{labels},quantile="0.99"}} 1"#),
+            format!("microsim_rpc_seconds_sum{{{labels}}} 1.75"),
+            format!("microsim_rpc_seconds_count{{{labels}}} 3"),
+            "microsim_streams_active 1".to_string(),
+            "microsim_streams_total 1".to_string(),
+            "microsim_reloads_total 0".to_string(),
+            "# TYPE microsim_component_power gauge".to_string(),
+            r#"microsim_component_power{component_id="2",category="meter"} 1500"#.to_string(),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "{expected} in:\n{out}");
+        }
+        assert!(!out.contains(r#"component_id="99""#));
+
+        config.shutdown().await.unwrap();
+    }
+}
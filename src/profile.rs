@@ -0,0 +1,549 @@
+use std::{any::Any, cell::RefCell, collections::HashMap, f64::consts::PI, rc::Rc};
+
+use tulisp::{destruct_bind, Error, ErrorKind, TulispContext, TulispObject};
+
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// Seconds of simulated time since the simulation started, from
+/// `simulation-time-ms`.
+fn simulation_seconds(ctx: &mut TulispContext) -> f64 {
+    ctx.intern("simulation-time-ms")
+        .get()
+        .and_then(|x| x.as_int())
+        .unwrap_or_default() as f64
+        / 1000.0
+}
+
+/// The simulated wall-clock time, in seconds since the unix epoch.  It
+/// starts at `simulation-start-time` and follows the simulated time.
+fn simulation_clock(ctx: &mut TulispContext) -> Result<f64, Error> {
+    let start = ctx.intern("simulation-start-time").get()?.try_float()?;
+    Ok(start + simulation_seconds(ctx))
+}
+
+#[derive(Clone, Copy)]
+enum Interpolation {
+    Linear,
+    Step,
+    Nearest,
+}
+
+#[derive(Clone, Copy)]
+enum TimeFormat {
+    /// Seconds from the start of the profile.
+    Offset,
+    /// Unix timestamps in seconds, or `YYYY-MM-DD[T ]HH:MM:SS[Z|±HH:MM]`.
+    Timestamp,
+}
+
+#[derive(Clone, Copy)]
+enum Origin {
+    /// Offsets are seconds since UTC midnight.
+    Midnight,
+    /// Offsets are seconds of simulated time since the profile was
+    /// loaded.
+    Start,
+}
+
+/// A time series that can be sampled from lisp with `profile-value`.
+///
+/// Points are `(offset-seconds, value)` pairs, sorted by offset.
+pub(crate) struct Profile {
+    points: Vec<(f64, f64)>,
+    interpolation: Interpolation,
+    origin: Origin,
+    /// When set, the profile repeats after this many seconds.
+    period: Option<f64>,
+    time_scale: f64,
+    /// The simulated time the profile starts at, in seconds.
+    start: f64,
+}
+
+struct CsvOptions {
+    time_column: usize,
+    value_column: usize,
+    time_format: TimeFormat,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            time_column: 0,
+            value_column: 1,
+            time_format: TimeFormat::Offset,
+        }
+    }
+}
+
+fn read_csv(filename: &str, opts: &CsvOptions) -> Result<Vec<(f64, f64)>, Error> {
+    let contents = std::fs::read_to_string(filename).map_err(|e| {
+        Error::new(
+            ErrorKind::Undefined,
+            format!("Unable to read profile {filename}: {e}"),
+        )
+    })?;
+
+    let mut points = Vec::new();
+    for (num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cols = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let (Some(time), Some(value)) = (cols.get(opts.time_column), cols.get(opts.value_column))
+        else {
+            return Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("{filename}:{}: missing columns in {line:?}", num + 1),
+            ));
+        };
+        let time = match opts.time_format {
+            TimeFormat::Offset => time.parse::<f64>().ok(),
+            TimeFormat::Timestamp => parse_timestamp(time),
+        };
+        match (time, value.parse::<f64>()) {
+            (Some(time), Ok(value)) => points.push((time, value)),
+            // Allow a header line.
+            _ if points.is_empty() => continue,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::TypeMismatch,
+                    format!("{filename}:{}: invalid value in {line:?}", num + 1),
+                ))
+            }
+        }
+    }
+
+    if points.is_empty() {
+        return Err(Error::new(
+            ErrorKind::Undefined,
+            format!("Profile {filename} has no data points"),
+        ));
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // Recorded timestamps are replayed from the first sample onwards.
+    if let TimeFormat::Timestamp = opts.time_format {
+        let first = points[0].0;
+        points.iter_mut().for_each(|(t, _)| *t -= first);
+    }
+
+    Ok(points)
+}
+
+/// Parses a unix timestamp in seconds, or an ISO 8601 date-time, into
+/// seconds since the unix epoch.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    if let Ok(secs) = s.parse::<f64>() {
+        return Some(secs);
+    }
+
+    let (date, time) = s.split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-').map(|x| x.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+
+    let (time, tz_offset) = if let Some(time) = time.strip_suffix('Z') {
+        (time, 0.0)
+    } else if let Some(pos) = time.rfind(['+', '-']) {
+        let (time, tz) = time.split_at(pos);
+        let sign = if tz.starts_with('-') { -1.0 } else { 1.0 };
+        let (hh, mm) = tz[1..].split_once(':').unwrap_or((&tz[1..], "0"));
+        let tz = hh.parse::<f64>().ok()? * 3600.0 + mm.parse::<f64>().ok()? * 60.0;
+        (time, sign * tz)
+    } else {
+        (time, 0.0)
+    };
+    let mut time = time.splitn(3, ':').map(|x| x.parse::<f64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next().flatten()?);
+
+    Some(
+        days_from_civil(year, month, day) as f64 * SECONDS_PER_DAY
+            + hours * 3600.0
+            + minutes * 60.0
+            + seconds
+            - tz_offset,
+    )
+}
+
+/// Days since the unix epoch for the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl Profile {
+    /// A profile indexed by the time of day, that repeats every day.
+    fn daily(points: Vec<(f64, f64)>) -> Self {
+        Self {
+            points,
+            interpolation: Interpolation::Linear,
+            origin: Origin::Midnight,
+            period: Some(SECONDS_PER_DAY),
+            time_scale: 1.0,
+            start: 0.0,
+        }
+    }
+
+    /// A profile that is replayed from `start`, in seconds of simulated
+    /// time.
+    fn replay(points: Vec<(f64, f64)>, looping: bool, start: f64) -> Self {
+        // Keep the last sample for as long as the one before it, before
+        // looping back to the first sample.
+        let period = looping.then(|| {
+            let n = points.len();
+            let step = if n > 1 {
+                points[n - 1].0 - points[n - 2].0
+            } else {
+                1.0
+            };
+            points[n - 1].0 - points[0].0 + step
+        });
+        Self {
+            points,
+            interpolation: Interpolation::Linear,
+            origin: Origin::Start,
+            period,
+            time_scale: 1.0,
+            start,
+        }
+    }
+
+    /// Returns the value of the profile at the current simulated time.
+    fn value(&self, ctx: &mut TulispContext) -> Result<f64, Error> {
+        let offset = match self.origin {
+            Origin::Midnight => simulation_clock(ctx)?.rem_euclid(SECONDS_PER_DAY),
+            Origin::Start => simulation_seconds(ctx) - self.start,
+        };
+        Ok(self.value_at(offset * self.time_scale))
+    }
+
+    fn value_at(&self, offset: f64) -> f64 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        let offset = match self.period {
+            Some(period) => first.0 + (offset - first.0).rem_euclid(period),
+            None => offset,
+        };
+
+        let idx = self.points.partition_point(|(t, _)| *t <= offset);
+        // When looping, the last point interpolates into the first point
+        // of the next period.
+        let (prev, next) = match (idx, self.period) {
+            (0, Some(period)) => ((last.0 - period, last.1), first),
+            (0, None) => return first.1,
+            (idx, Some(period)) if idx == self.points.len() => (last, (first.0 + period, first.1)),
+            (idx, None) if idx == self.points.len() => return last.1,
+            (idx, _) => (self.points[idx - 1], self.points[idx]),
+        };
+        if next.0 <= prev.0 {
+            return prev.1;
+        }
+
+        match self.interpolation {
+            Interpolation::Linear => {
+                prev.1 + (next.1 - prev.1) * (offset - prev.0) / (next.0 - prev.0)
+            }
+            Interpolation::Step => prev.1,
+            Interpolation::Nearest => {
+                if offset - prev.0 < next.0 - offset {
+                    prev.1
+                } else {
+                    next.1
+                }
+            }
+        }
+    }
+}
+
+/// Returns the clear-sky PV production at the given location and time,
+/// in seconds since the unix epoch, as a percentage of the production
+/// at 1000 W/m² irradiance.
+///
+/// Uses a simple solar position model and the Haurwitz clear-sky
+/// irradiance model, which is good enough to produce realistic daily
+/// shapes and seasonal variation.
+fn clear_sky_percent(latitude: f64, longitude: f64, secs: f64) -> f64 {
+    let day_of_year = (secs / SECONDS_PER_DAY).rem_euclid(365.25);
+    let utc_hours = secs.rem_euclid(SECONDS_PER_DAY) / 3600.0;
+
+    let declination = -23.44_f64.to_radians() * (2.0 * PI * (day_of_year + 10.0) / 365.25).cos();
+    let solar_hours = utc_hours + longitude / 15.0;
+    let hour_angle = (15.0 * (solar_hours - 12.0)).to_radians();
+    let latitude = latitude.to_radians();
+
+    let sin_elevation =
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    if sin_elevation <= 0.0 {
+        return 0.0;
+    }
+
+    let irradiance = 1098.0 * sin_elevation * (-0.057 / sin_elevation).exp();
+    (irradiance / 10.0).min(100.0)
+}
+
+fn invalid_option(key: &str, val: &TulispObject) -> Error {
+    Error::new(
+        ErrorKind::TypeMismatch,
+        format!("Invalid value for {key}: {val}"),
+    )
+}
+
+fn column_index(key: &str, val: &TulispObject) -> Result<usize, Error> {
+    usize::try_from(val.as_int()?).map_err(|_| invalid_option(key, val))
+}
+
+/// Parses the keyword arguments of `load-profile` and loads the file.
+/// The profile starts at `start`, in seconds of simulated time.
+fn load_profile(
+    ctx: &mut TulispContext,
+    filename: &str,
+    plist: &TulispObject,
+    start: f64,
+) -> Result<Profile, Error> {
+    let mut csv_opts = CsvOptions::default();
+    let mut interpolation = Interpolation::Linear;
+    let mut looping = true;
+    let mut time_scale = 1.0;
+
+    let mut rest = plist.clone();
+    while rest.consp() {
+        let key = rest.car()?.as_symbol()?;
+        let val = ctx.eval(&rest.cadr()?)?;
+        rest = rest.cddr()?;
+
+        match key.as_str() {
+            ":time-column" => csv_opts.time_column = column_index(&key, &val)?,
+            ":value-column" => csv_opts.value_column = column_index(&key, &val)?,
+            ":time-format" => {
+                csv_opts.time_format = match val.as_symbol()?.as_str() {
+                    "offset" => TimeFormat::Offset,
+                    "timestamp" => TimeFormat::Timestamp,
+                    _ => return Err(invalid_option(&key, &val)),
+                }
+            }
+            ":interpolation" => {
+                interpolation = match val.as_symbol()?.as_str() {
+                    "linear" => Interpolation::Linear,
+                    "step" => Interpolation::Step,
+                    "nearest" => Interpolation::Nearest,
+                    _ => return Err(invalid_option(&key, &val)),
+                }
+            }
+            ":loop" => looping = !val.null(),
+            ":time-scale" => {
+                time_scale = val.try_float()?;
+                if time_scale <= 0.0 {
+                    return Err(invalid_option(&key, &val));
+                }
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Undefined,
+                    format!("Unknown load-profile option: {key}"),
+                ))
+            }
+        }
+    }
+
+    let mut profile = Profile::replay(read_csv(filename, &csv_opts)?, looping, start);
+    profile.interpolation = interpolation;
+    profile.time_scale = time_scale;
+    Ok(profile)
+}
+
+pub(crate) fn add_functions(ctx: &mut TulispContext) {
+    ctx.add_special_form("load-daily-profile", |ctx, args| {
+        destruct_bind!((filename) = args);
+        let filename = ctx.eval(&filename)?.as_string()?;
+        let profile = Profile::daily(read_csv(&filename, &CsvOptions::default())?);
+        Ok(TulispObject::from(Rc::new(profile) as Rc<dyn Any>))
+    });
+
+    // Files are replayed from when they were first loaded, so that
+    // reloading the config file doesn't restart them.
+    let start_times = Rc::new(RefCell::new(HashMap::<String, f64>::new()));
+    ctx.add_special_form("load-profile", move |ctx, args| {
+        destruct_bind!((filename &rest plist) = args);
+        let filename = ctx.eval(&filename)?.as_string()?;
+        let start = match start_times.borrow().get(&filename) {
+            Some(&start) => start,
+            None => simulation_seconds(ctx),
+        };
+        let profile = load_profile(ctx, &filename, &plist, start)?;
+        start_times.borrow_mut().insert(filename, start);
+        Ok(TulispObject::from(Rc::new(profile) as Rc<dyn Any>))
+    });
+
+    ctx.add_special_form("profile-value", |ctx, args| {
+        destruct_bind!((profile) = args);
+        let obj = ctx.eval(&profile)?;
+        let any = obj.as_any()?;
+        let Some(profile) = any.downcast_ref::<Profile>() else {
+            return Err(Error::new(
+                ErrorKind::TypeMismatch,
+                format!("Not a profile: {}", obj),
+            ));
+        };
+        Ok(profile.value(ctx)?.into())
+    });
+
+    ctx.add_special_form("clear-sky%", |ctx, args| {
+        destruct_bind!((&optional location) = args);
+        let location = if location.null() {
+            let metadata = ctx.intern("metadata").get().unwrap_or_default();
+            let key = ctx.intern("location");
+            tulisp::lists::alist_get(ctx, &key, &metadata, None, None, None)?
+        } else {
+            ctx.eval(&location)?
+        };
+        if !location.consp() {
+            return Err(Error::new(
+                ErrorKind::Undefined,
+                "clear-sky% needs a location.  Add `(location . (latitude longitude))' \
+                 to `metadata', or pass it as an argument"
+                    .to_string(),
+            ));
+        }
+        let latitude = location.car()?.try_float()?;
+        let longitude = location.cadr()?.try_float()?;
+        let time = simulation_clock(ctx)?;
+        Ok(clear_sky_percent(latitude, longitude, time).into())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1700000000"), Some(1700000000.0));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0.0));
+        assert_eq!(
+            parse_timestamp("2024-03-01 12:30:15+01:00"),
+            Some(1709292615.0)
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-01T10:00:15-01:30"),
+            Some(1709292615.0)
+        );
+        assert_eq!(parse_timestamp("2024-03-01T12:30"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn counts_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+    }
+
+    #[test]
+    fn interpolates() {
+        let mut profile = Profile::replay(vec![(0.0, 0.0), (10.0, 100.0)], false, 0.0);
+        assert_eq!(profile.value_at(-1.0), 0.0);
+        assert_eq!(profile.value_at(5.0), 50.0);
+        assert_eq!(profile.value_at(20.0), 100.0);
+
+        profile.interpolation = Interpolation::Step;
+        assert_eq!(profile.value_at(5.0), 0.0);
+        assert_eq!(profile.value_at(10.0), 100.0);
+
+        profile.interpolation = Interpolation::Nearest;
+        assert_eq!(profile.value_at(4.0), 0.0);
+        assert_eq!(profile.value_at(6.0), 100.0);
+    }
+
+    #[test]
+    fn wraps_looping_profiles() {
+        // The first sample follows the last one after the interval
+        // between the last two samples.
+        let profile = Profile::replay(vec![(0.0, 0.0), (10.0, 100.0)], true, 0.0);
+        assert_eq!(profile.period, Some(20.0));
+        assert_eq!(profile.value_at(15.0), 50.0);
+        assert_eq!(profile.value_at(25.0), 50.0);
+        assert_eq!(profile.value_at(-5.0), 50.0);
+    }
+
+    #[test]
+    fn follows_simulated_time() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"time,power\n0,0\n10,100\n").unwrap();
+        let filename = file.path().to_str().unwrap();
+
+        let mut ctx = TulispContext::new();
+        add_functions(&mut ctx);
+        let mut value_at = |time_ms: i64| {
+            ctx.eval_string(&format!(
+                r#"(setq simulation-time-ms {time_ms})
+                   (profile-value (load-profile "{filename}" :loop nil))"#
+            ))
+            .unwrap()
+            .try_float()
+            .unwrap()
+        };
+        // The profile starts when it is first loaded, and stays anchored
+        // there when it is loaded again, like on a reload.
+        assert_eq!(value_at(5000), 0.0);
+        assert_eq!(value_at(10000), 50.0);
+        assert_eq!(value_at(12500), 75.0);
+        assert_eq!(value_at(60000), 100.0);
+    }
+
+    #[test]
+    fn follows_the_simulation_clock() {
+        let mut ctx = TulispContext::new();
+        add_functions(&mut ctx);
+        // 2024-06-21, midnight UTC.
+        ctx.eval_string("(setq simulation-start-time 1718928000.0)")
+            .unwrap();
+        let mut clear_sky_at = |time_ms: i64| {
+            ctx.eval_string(&format!(
+                "(setq simulation-time-ms {time_ms}) (clear-sky% '(52.52 13.405))"
+            ))
+            .unwrap()
+            .try_float()
+            .unwrap()
+        };
+        assert_eq!(clear_sky_at(0), 0.0);
+        assert!(clear_sky_at(11 * 3600 * 1000) > 80.0);
+        assert_eq!(clear_sky_at(22 * 3600 * 1000), 0.0);
+    }
+
+    #[test]
+    fn needs_a_location_for_clear_sky() {
+        let mut ctx = TulispContext::new();
+        add_functions(&mut ctx);
+        ctx.eval_string("(setq simulation-start-time 0.0) (setq metadata nil)")
+            .unwrap();
+        let err = ctx.eval_string("(clear-sky%)").unwrap_err().desc();
+        assert!(err.starts_with("clear-sky% needs a location"), "{err}");
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let mut ctx = TulispContext::new();
+        add_functions(&mut ctx);
+        for opts in [
+            ":time-column -1",
+            ":value-column -2",
+            ":time-scale 0",
+            ":time-scale -1.5",
+        ] {
+            let err = ctx
+                .eval_string(&format!(r#"(load-profile "missing.csv" {opts})"#))
+                .err()
+                .map(|err| err.desc());
+            assert!(
+                err.as_deref()
+                    .is_some_and(|err| err.starts_with("Invalid value for")),
+                "{opts}: {err:?}"
+            );
+        }
+    }
+}
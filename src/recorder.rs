@@ -0,0 +1,252 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prost::Message;
+use serde_json::{json, Value};
+
+use crate::proto::microgrid::{ComponentData, ComponentList, ConnectionList};
+use crate::rpc_faults::RpcOutcome;
+
+/// Writes the topology, the component data sent to each client and
+/// every received RPC call to a JSONL file, one record per line.
+///
+/// Component data is recorded as it is sent, after comm faults and
+/// measurement errors, so that the recording shows exactly what each
+/// client received.
+///
+/// Protobuf messages are stored base64-encoded in `data`, so that
+/// recordings can be replayed exactly, with a readable `summary` next
+/// to them.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    tx: tokio::sync::mpsc::UnboundedSender<Message>,
+}
+
+enum Message {
+    Record(Value),
+    /// Flushes the file, and then notifies the sender.
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn encode(msg: &impl Message) -> String {
+    BASE64.encode(msg.encode_to_vec())
+}
+
+fn summary(data: &ComponentData) -> Value {
+    let (category, values) = crate::metrics::component_values(data);
+    let mut summary = json!({ "category": category });
+    for (name, value) in values {
+        summary[name] = value.into();
+    }
+    summary
+}
+
+impl Recorder {
+    /// Creates the recording, and starts writing to it on a task of the
+    /// current runtime.
+    pub(crate) async fn new(filename: &str) -> std::io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let filename = filename.to_string();
+        log::info!("Recording to {}", filename);
+
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let mut res = match msg {
+                    Message::Record(record) => writeln!(writer, "{record}"),
+                    Message::Flush(done) => {
+                        let res = writer.flush();
+                        let _ = done.send(());
+                        res
+                    }
+                };
+                if res.is_ok() && rx.is_empty() {
+                    res = writer.flush();
+                }
+                if let Err(err) = res {
+                    log::error!("Unable to write to recording {filename}: {err}");
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { tx })
+    }
+
+    fn write(&self, kind: &str, mut record: Value) {
+        record["ts"] = unix_ms().into();
+        record["kind"] = kind.into();
+        let _ = self.tx.send(Message::Record(record));
+    }
+
+    /// Waits until the records so far are written to the file.
+    pub(crate) async fn flush(&self) {
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        if self.tx.send(Message::Flush(done_tx)).is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+
+    pub(crate) fn topology(&self, components: &ComponentList, connections: &ConnectionList) {
+        self.write(
+            "topology",
+            json!({
+                "components": encode(components),
+                "connections": encode(connections),
+            }),
+        );
+    }
+
+    /// Records an item of a component's data stream, as it is sent to
+    /// `client`, after any comm faults.  Errors end the stream.
+    pub(crate) fn sent(
+        &self,
+        client: &str,
+        component_id: u64,
+        item: &Result<ComponentData, tonic::Status>,
+    ) {
+        match item {
+            Ok(data) => self.write(
+                "component-data",
+                json!({
+                    "component_id": component_id,
+                    "client": client,
+                    "summary": summary(data),
+                    "data": encode(data),
+                }),
+            ),
+            Err(status) => self.write(
+                "stream-error",
+                json!({
+                    "component_id": component_id,
+                    "client": client,
+                    "error": status.to_string(),
+                }),
+            ),
+        }
+    }
+
+    /// Records an RPC call, with its outcome, and the error it failed
+    /// with, if any.
+    pub(crate) fn rpc(
+        &self,
+        method: &str,
+        client: Option<SocketAddr>,
+        params: Value,
+        outcome: &RpcOutcome,
+    ) {
+        let error = match outcome {
+            RpcOutcome::Fail(status) => Some(status.to_string()),
+            _ => None,
+        };
+        self.write(
+            "rpc",
+            json!({
+                "method": method,
+                "client": client.map(|c| c.to_string()),
+                "params": params,
+                "outcome": outcome.name(),
+                "error": error,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        proto::microgrid::{microgrid_server::Microgrid, ComponentIdParam},
+        server::MicrogridServer,
+    };
+    use tokio_stream::StreamExt;
+
+    fn read(filename: &std::path::Path) -> Vec<Value> {
+        std::fs::read_to_string(filename)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_the_samples_sent_to_each_client() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let config = Config::from_source(
+            "(make-grid :id 1 :successors (list (make-meter :id 2 :interval 50)))",
+        )
+        .await
+        .unwrap();
+        let recorder = Recorder::new(file.path().to_str().unwrap()).await.unwrap();
+        let server = MicrogridServer::new(config.clone()).with_recorder(recorder);
+
+        let mut request = tonic::Request::new(ComponentIdParam { id: 2 });
+        request
+            .metadata_mut()
+            .insert("x-client-id", "sdk-app".parse().unwrap());
+        let mut stream = server
+            .stream_component_data(request)
+            .await
+            .unwrap()
+            .into_inner();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(stream.next().await.unwrap().unwrap());
+        }
+        drop(stream);
+        drop(server);
+        // Let the writer catch up, on this single-threaded runtime.
+        tokio::task::yield_now().await;
+
+        let records = read(file.path());
+        assert_eq!(records[0]["kind"], "topology");
+        let sent: Vec<_> = records
+            .iter()
+            .filter(|r| r["kind"] == "component-data")
+            .collect();
+        assert!(sent.len() >= 3);
+        for (record, data) in sent.iter().zip(&received) {
+            assert_eq!(record["component_id"], 2);
+            assert_eq!(record["client"], "sdk-app");
+            assert_eq!(record["data"], encode(data));
+        }
+
+        config.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn records_rpc_calls_and_stream_errors() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let recorder = Recorder::new(file.path().to_str().unwrap()).await.unwrap();
+        recorder.rpc(
+            "set-power-active",
+            None,
+            json!({"component_id": 2}),
+            &RpcOutcome::Ignore,
+        );
+        recorder.sent("sdk-app", 2, &Err(tonic::Status::unavailable("gone")));
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+
+        let records = read(file.path());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["kind"], "rpc");
+        assert_eq!(records[0]["outcome"], "ignore");
+        assert_eq!(records[0]["error"], Value::Null);
+        assert_eq!(records[1]["kind"], "stream-error");
+        assert_eq!(records[1]["client"], "sdk-app");
+        assert_eq!(records[1]["component_id"], 2);
+    }
+}
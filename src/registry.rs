@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    measurement::MeasurementModel,
+    proto::{
+        common::components::{ComponentCategory, InverterType},
+        microgrid::{component, Component, ComponentList, ConnectionList},
+    },
+};
+
+/// Kinds of components whose power requests expire, when they aren't
+/// renewed within `retain-requests-duration-ms`.  Configured with
+/// `request-timeout-categories`.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum TimeoutCategory {
+    BatteryInverter,
+    SolarInverter,
+    EvCharger,
+    Chp,
+}
+
+impl TimeoutCategory {
+    pub(crate) fn of(component: &Component) -> Option<Self> {
+        match (component.category(), &component.metadata) {
+            (ComponentCategory::Inverter, Some(component::Metadata::Inverter(inverter))) => {
+                match inverter.r#type() {
+                    InverterType::Battery => Some(Self::BatteryInverter),
+                    InverterType::Solar => Some(Self::SolarInverter),
+                    _ => None,
+                }
+            }
+            (ComponentCategory::EvCharger, _) => Some(Self::EvCharger),
+            (ComponentCategory::Chp, _) => Some(Self::Chp),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for TimeoutCategory {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "battery-inverter" => Ok(Self::BatteryInverter),
+            "solar-inverter" => Ok(Self::SolarInverter),
+            "ev-charger" => Ok(Self::EvCharger),
+            "chp" => Ok(Self::Chp),
+            _ => Err(format!("Invalid request timeout category: {name}")),
+        }
+    }
+}
+
+/// What happens to a component when its power request expires.
+/// Decided per component by the lisp function
+/// `request-timeout-fallback-for`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Fallback {
+    /// Sets the power to zero.
+    Zero,
+    /// Keeps the last requested power.
+    Hold,
+    /// Sets the power to a default setpoint.
+    Setpoint(f32),
+    /// Sets the power to zero, and switches the component to standby.
+    Standby,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "zero" => Ok(Self::Zero),
+            "hold" => Ok(Self::Hold),
+            "standby" => Ok(Self::Standby),
+            _ => Err(format!("Invalid request timeout fallback: {name}")),
+        }
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zero => f.write_str("zero"),
+            Self::Hold => f.write_str("hold"),
+            Self::Setpoint(power) => write!(f, "setpoint {power}"),
+            Self::Standby => f.write_str("standby"),
+        }
+    }
+}
+
+/// The data stream of a component.
+pub(crate) struct Stream {
+    /// Milliseconds between samples.
+    pub(crate) interval: u64,
+    /// The sensor errors added to the component's AC values.
+    pub(crate) measurement: Option<MeasurementModel>,
+}
+
+/// The components and connections of the simulation, compiled from
+/// the lisp alists when the config is (re)loaded, so that requests
+/// don't have to parse them.
+///
+/// The registry is immutable.  A reload builds a new one, and swaps it
+/// in only when all components could be compiled.
+#[derive(Default)]
+pub(crate) struct Registry {
+    components: ComponentList,
+    connections: ConnectionList,
+    hidden_components: ComponentList,
+    hidden_connections: ConnectionList,
+
+    /// Component ID -> Index in `components`.
+    index: HashMap<u64, usize>,
+
+    /// Component ID -> Stream, for components that stream data.
+    streams: HashMap<u64, Stream>,
+
+    /// Component ID -> Fallback, for components whose power requests
+    /// expire.
+    request_timeouts: HashMap<u64, Fallback>,
+
+    /// Whether commands from other clients are rejected while a client
+    /// controls a component.
+    single_controller: bool,
+}
+
+impl Registry {
+    pub(crate) fn new(
+        components: ComponentList,
+        connections: ConnectionList,
+        hidden_components: ComponentList,
+        hidden_connections: ConnectionList,
+        streams: HashMap<u64, Stream>,
+        request_timeouts: HashMap<u64, Fallback>,
+        single_controller: bool,
+    ) -> Self {
+        let index = components
+            .components
+            .iter()
+            .enumerate()
+            .map(|(idx, c)| (c.id, idx))
+            .collect();
+        Self {
+            components,
+            connections,
+            hidden_components,
+            hidden_connections,
+            index,
+            streams,
+            request_timeouts,
+            single_controller,
+        }
+    }
+
+    pub(crate) fn components(&self) -> &ComponentList {
+        &self.components
+    }
+
+    pub(crate) fn connections(&self) -> &ConnectionList {
+        &self.connections
+    }
+
+    /// Components that are simulated but not exposed over the API, like
+    /// meters marked with `:hidden`.
+    pub(crate) fn hidden_components(&self) -> &ComponentList {
+        &self.hidden_components
+    }
+
+    /// Connections to and from hidden components.
+    pub(crate) fn hidden_connections(&self) -> &ConnectionList {
+        &self.hidden_connections
+    }
+
+    pub(crate) fn component(&self, id: u64) -> Option<&Component> {
+        self.index
+            .get(&id)
+            .map(|&idx| &self.components.components[idx])
+    }
+
+    pub(crate) fn category(&self, id: u64) -> Option<ComponentCategory> {
+        self.component(id).map(|c| c.category())
+    }
+
+    /// The interval of the component's data stream, if it has one.
+    pub(crate) fn stream_interval(&self, id: u64) -> Option<u64> {
+        self.streams.get(&id).map(|stream| stream.interval)
+    }
+
+    /// How the component measures its AC values, if it adds sensor
+    /// errors to them.
+    pub(crate) fn measurement(&self, id: u64) -> Option<&MeasurementModel> {
+        self.streams
+            .get(&id)
+            .and_then(|stream| stream.measurement.as_ref())
+    }
+
+    /// Whether power requests to the component expire.
+    pub(crate) fn expires_requests(&self, id: u64) -> bool {
+        self.request_timeouts.contains_key(&id)
+    }
+
+    /// Whether commands from other clients are rejected while a client
+    /// controls a component, from `single-controller`.
+    pub(crate) fn single_controller(&self) -> bool {
+        self.single_controller
+    }
+
+    /// What happens to the component when its power request expires.
+    pub(crate) fn fallback(&self, id: u64) -> Option<Fallback> {
+        self.request_timeouts.get(&id).copied()
+    }
+}
+
+/// The current registry, shared between the simulation thread, which
+/// replaces it on reload, and the handles that read it.
+pub(crate) type SharedRegistry = Arc<RwLock<Arc<Registry>>>;
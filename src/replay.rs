@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use prost::Message;
+use serde_json::Value;
+
+use crate::proto::microgrid::{ComponentData, ComponentList, ConnectionList};
+
+/// Component data and topology loaded from a recording, which the
+/// server streams instead of simulated data.
+///
+/// Recordings are in the JSONL format written by the `Recorder`.  Data
+/// from other sources can be replayed after converting it to the two
+/// kinds of records that are read:
+///
+///   - `{"kind": "topology", "components": ..., "connections": ...}`,
+///     with a base64-encoded `ComponentList` and `ConnectionList`.
+///     Only the first one is used.
+///   - `{"kind": "component-data", "ts": ..., "data": ...}`, with the
+///     time of the sample in Unix milliseconds, and a base64-encoded
+///     `ComponentData`.
+///
+/// Records have to be in time order.  Other records and fields are
+/// ignored.  A sample that is recorded more than once, with the same
+/// timestamp and values, is only replayed once.  The samples of each component are streamed with
+/// their recorded intervals, divided by `speed`, and start over at the
+/// end of the recording.
+pub(crate) struct Replay {
+    components: ComponentList,
+    connections: ConnectionList,
+    /// Component ID -> (time since the start of the recording, sample)
+    samples: HashMap<u64, Vec<(Duration, ComponentData)>>,
+    speed: f64,
+}
+
+fn decode<T: Message + Default>(record: &Value, key: &str) -> Result<T, String> {
+    let data = record[key]
+        .as_str()
+        .ok_or_else(|| format!("Missing `{key}` in record"))?;
+    let bytes = BASE64.decode(data).map_err(|e| e.to_string())?;
+    T::decode(bytes.as_slice()).map_err(|e| e.to_string())
+}
+
+impl Replay {
+    pub(crate) fn load(filename: &str, speed: f64) -> Result<Self, String> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(format!("Invalid replay speed: {speed}"));
+        }
+        let file = File::open(filename).map_err(|e| e.to_string())?;
+
+        let mut topology = None;
+        let mut samples: HashMap<u64, Vec<(Duration, ComponentData)>> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut start_ts = None;
+
+        for (num, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Value =
+                serde_json::from_str(&line).map_err(|e| format!("{filename}:{}: {e}", num + 1))?;
+
+            match record["kind"].as_str() {
+                Some("topology") if topology.is_none() => {
+                    topology = Some((
+                        decode::<ComponentList>(&record, "components")?,
+                        decode::<ConnectionList>(&record, "connections")?,
+                    ));
+                }
+                Some("component-data") => {
+                    let ts = record["ts"]
+                        .as_u64()
+                        .ok_or_else(|| format!("{filename}:{}: Missing `ts` in record", num + 1))?;
+                    let data = decode::<ComponentData>(&record, "data")
+                        .map_err(|e| format!("{filename}:{}: {e}", num + 1))?;
+                    // The encoded sample has its own timestamp, so only
+                    // exact copies are dropped.
+                    if !seen.insert(data.encode_to_vec()) {
+                        continue;
+                    }
+                    let start_ts = *start_ts.get_or_insert(ts);
+                    samples
+                        .entry(data.id)
+                        .or_default()
+                        .push((Duration::from_millis(ts.saturating_sub(start_ts)), data));
+                }
+                _ => {}
+            }
+        }
+
+        let Some((components, connections)) = topology else {
+            return Err(format!("No topology found in {filename}"));
+        };
+        log::info!(
+            "Loaded recording {} with {} components",
+            filename,
+            samples.len()
+        );
+
+        Ok(Self {
+            components,
+            connections,
+            samples,
+            speed,
+        })
+    }
+
+    pub(crate) fn components(&self) -> ComponentList {
+        self.components.clone()
+    }
+
+    pub(crate) fn connections(&self) -> ConnectionList {
+        self.connections.clone()
+    }
+
+    /// Returns the recorded samples of a component, with the delay
+    /// after the previous sample, adjusted for the replay speed.  The
+    /// first sample follows the last one with the recorded interval
+    /// between the first two samples, so that the samples can be
+    /// looped over.
+    pub(crate) fn samples(&self, component_id: u64) -> Option<Vec<(Duration, ComponentData)>> {
+        let samples = self.samples.get(&component_id)?;
+        let first = samples.first()?.0;
+        let interval = samples
+            .get(1)
+            .map(|(offset, _)| offset.saturating_sub(first))
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(Duration::from_secs(1));
+        let mut prev = None;
+        Some(
+            samples
+                .iter()
+                .map(|(offset, data)| {
+                    let delay = prev.map_or(interval, |prev| offset.saturating_sub(prev));
+                    prev = Some(*offset);
+                    (delay.div_f64(self.speed), data.clone())
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encode(msg: &impl Message) -> String {
+        BASE64.encode(msg.encode_to_vec())
+    }
+
+    /// A sample recorded at `ts`, with its own timestamp `sample_ts`,
+    /// both in Unix milliseconds.
+    fn sample(id: u64, ts: u64, sample_ts: u64) -> String {
+        let data = ComponentData {
+            id,
+            ts: Some(prost_types::Timestamp {
+                seconds: (sample_ts / 1000) as i64,
+                nanos: (sample_ts % 1000) as i32 * 1_000_000,
+            }),
+            ..Default::default()
+        };
+        json!({"kind": "component-data", "ts": ts, "data": encode(&data)}).to_string()
+    }
+
+    fn topology() -> String {
+        json!({
+            "kind": "topology",
+            "components": encode(&ComponentList::default()),
+            "connections": encode(&ConnectionList::default()),
+        })
+        .to_string()
+    }
+
+    fn load_at(lines: &[String], speed: f64) -> Result<Replay, String> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), lines.join("\n")).unwrap();
+        Replay::load(file.path().to_str().unwrap(), speed)
+    }
+
+    fn load(lines: &[String]) -> Result<Replay, String> {
+        load_at(lines, 2.0)
+    }
+
+    #[test]
+    fn loads_each_sample_once() {
+        let replay = load(&[
+            topology(),
+            sample(1, 1000, 990),
+            // Recorded twice, like a stale repeat.
+            sample(1, 1000, 990),
+            sample(2, 1000, 990),
+            // Distinct samples, recorded in the same millisecond.
+            sample(2, 1000, 995),
+            sample(1, 1200, 1190),
+            sample(1, 1400, 1390),
+        ])
+        .unwrap();
+
+        let delays: Vec<_> = replay
+            .samples(1)
+            .unwrap()
+            .into_iter()
+            .map(|(delay, _)| delay)
+            .collect();
+        // Intervals of 200ms at double speed, with the first sample
+        // following the last one.
+        assert_eq!(delays, [Duration::from_millis(100); 3]);
+        assert_eq!(replay.samples(2).unwrap().len(), 2);
+        assert!(replay.samples(3).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_speeds() {
+        for speed in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let err = load_at(&[topology()], speed);
+            assert!(err.is_err_and(|e| e.starts_with("Invalid replay speed")));
+        }
+    }
+
+    #[test]
+    fn rejects_recordings_without_topology_or_timestamps() {
+        let err = load(&[sample(1, 1000, 1000)]);
+        assert!(err.is_err_and(|e| e.starts_with("No topology found")));
+
+        let line = json!({"kind": "component-data", "data": ""}).to_string();
+        let err = load(&[line]);
+        assert!(err.is_err_and(|e| e.ends_with("Missing `ts` in record")));
+    }
+}
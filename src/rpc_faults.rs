@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tulisp::{Error, TulispContext};
+
+use crate::alist::{get, get_float, status_code};
+
+/// Failures injected into a unary RPC method.
+///
+/// Configured with the `rpc-faults` variable, as an alist from method
+/// names like `set-power-active` or `list-components`, to alists with
+/// these optional keys:
+///
+///   - `components`: the component IDs the faults apply to, or all
+///     components if not set.
+///   - `error-rate`: probability of the call failing.
+///   - `status`: the gRPC status to fail with, like `unavailable`
+///     (default) or `deadline-exceeded`.
+///   - `delay-ms`: latency added to every call.
+///   - `ignore-rate`: probability of a command being accepted, but
+///     not applied.
+#[derive(Default)]
+pub(crate) struct RpcFaults {
+    error_rate: f64,
+    status: Option<tonic::Code>,
+    delay: Duration,
+    ignore_rate: f64,
+}
+
+/// What to do with an RPC call.
+pub(crate) enum RpcOutcome {
+    Proceed,
+    Ignore,
+    Fail(tonic::Status),
+}
+
+impl RpcOutcome {
+    /// The name of the outcome, for recordings.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Proceed => "proceed",
+            Self::Ignore => "ignore",
+            Self::Fail(_) => "fail",
+        }
+    }
+}
+
+impl RpcFaults {
+    /// Reads the faults configured for `method`, and for the given
+    /// component, if the method applies to one.
+    pub(crate) fn from_config(
+        ctx: &mut TulispContext,
+        method: &str,
+        component_id: Option<u64>,
+    ) -> Result<Self, Error> {
+        let faults = ctx.intern("rpc-faults").get().unwrap_or_default();
+        let alist = get(ctx, &faults, method)?;
+        if alist.null() {
+            return Ok(Self::default());
+        }
+
+        let components = get(ctx, &alist, "components")?;
+        if let (false, Some(id)) = (components.null(), component_id) {
+            let matches = components
+                .base_iter()
+                .any(|x| x.as_int().is_ok_and(|x| x as u64 == id));
+            if !matches {
+                return Ok(Self::default());
+            }
+        }
+
+        let status = get(ctx, &alist, "status")?;
+        let status = if status.null() {
+            None
+        } else {
+            Some(status_code(&status.as_symbol()?)?)
+        };
+
+        Ok(Self {
+            error_rate: get_float(ctx, &alist, "error-rate")?.unwrap_or_default(),
+            status,
+            delay: Duration::from_secs_f64(
+                get_float(ctx, &alist, "delay-ms")?
+                    .unwrap_or_default()
+                    .max(0.0)
+                    / 1000.0,
+            ),
+            ignore_rate: get_float(ctx, &alist, "ignore-rate")?.unwrap_or_default(),
+        })
+    }
+
+    pub(crate) fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub(crate) fn outcome(&self, method: &str) -> RpcOutcome {
+        let mut rng = rand::thread_rng();
+        if self.error_rate > 0.0 && rng.gen_bool(self.error_rate.min(1.0)) {
+            return RpcOutcome::Fail(tonic::Status::new(
+                self.status.unwrap_or(tonic::Code::Unavailable),
+                format!("{method} failed by rpc-faults"),
+            ));
+        }
+        if self.ignore_rate > 0.0 && rng.gen_bool(self.ignore_rate.min(1.0)) {
+            return RpcOutcome::Ignore;
+        }
+        RpcOutcome::Proceed
+    }
+}
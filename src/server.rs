@@ -1,66 +1,424 @@
-use std::collections::HashSet;
 use std::pin::Pin;
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use tokio::task::AbortHandle;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::Stream;
 
-use crate::lisp::Config;
+use serde_json::json;
+
+use crate::config::Config;
+use crate::metrics::Metrics;
 use crate::proto::common::components::ComponentCategory;
-use crate::proto::common::components::InverterType;
 use crate::proto::microgrid::microgrid_server::Microgrid;
 use crate::proto::microgrid::{
-    component, ComponentData, ComponentFilter, ComponentIdParam, ComponentList, ConnectionFilter,
+    ComponentData, ComponentFilter, ComponentIdParam, ComponentList, ConnectionFilter,
     ConnectionList, MicrogridMetadata, SetBoundsParam, SetPowerActiveParam, SetPowerReactiveParam,
 };
+use crate::recorder::Recorder;
+use crate::replay::Replay;
+use crate::rpc_faults::RpcOutcome;
+use crate::sessions::{client_of, Claim, Sessions};
 
 pub struct MicrogridServer {
     pub config: Config,
     pub timeout_tracker: crate::timeout_tracker::TimeoutTracker,
-    pub bat_inverter_ids: HashSet<u64>,
+    pub sessions: Sessions,
+    pub recorder: Option<Recorder>,
+    pub replay: Option<Arc<Replay>>,
+    /// Expires power requests, until the server is dropped.
+    timeout_tracker_task: AbortHandle,
 }
 
 impl MicrogridServer {
     pub fn new(config: Config) -> Self {
-        let bat_inv_ids = config
-            .components()
-            .unwrap()
-            .components
-            .iter()
-            .filter(|c| {
-                c.category == ComponentCategory::Inverter as i32
-                    && match c.metadata.as_ref().unwrap() {
-                        component::Metadata::Inverter(inverter) => {
-                            inverter.r#type == InverterType::Battery as i32
-                        }
-                        _ => false,
-                    }
-            })
-            .map(|c| c.id)
-            .collect();
-        let new = Self {
+        let timeout_tracker = crate::timeout_tracker::TimeoutTracker::new();
+        let sessions = Sessions::new(config.events());
+        let timeout_tracker_task =
+            Self::start_timeout_tracker(config.clone(), timeout_tracker.clone(), sessions.clone());
+        Self {
             config,
-            timeout_tracker: crate::timeout_tracker::TimeoutTracker::new(),
-            bat_inverter_ids: bat_inv_ids,
-        };
+            timeout_tracker,
+            sessions,
+            recorder: None,
+            replay: None,
+            timeout_tracker_task,
+        }
+    }
+
+    /// Records the topology, and the component data sent and RPC calls
+    /// received from here on.  When replaying, the replayed topology is
+    /// recorded, so `with_replay` has to be called first.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        match &self.replay {
+            Some(replay) => recorder.topology(&replay.components(), &replay.connections()),
+            None => {
+                let registry = self.config.registry();
+                recorder.topology(registry.components(), registry.connections());
+            }
+        }
+        self.recorder = Some(recorder);
+        self
+    }
 
-        new.start_timeout_tracker();
-        new
+    /// Serves the topology and component data from a recording,
+    /// instead of from the simulation.  Commands are still accepted,
+    /// but only logged.
+    pub fn with_replay(mut self, replay: Replay) -> Self {
+        self.replay = Some(Arc::new(replay));
+        self
     }
 
-    fn start_timeout_tracker(&self) {
-        let timeout_tracker = self.timeout_tracker.clone();
-        let config = self.config.clone();
+    /// Notes a received command, and returns whether it should be
+    /// applied, which it isn't when replaying a recording.
+    fn accept_command(&self, method: &str, component_id: u64) -> bool {
+        self.config.metrics().observe_command(component_id);
+        if self.replay.is_some() {
+            log::info!("{method}(component_id={component_id}): not applied in replay mode");
+            return false;
+        }
+        true
+    }
+
+    fn stream_replay(
+        replay: &Replay,
+        id: u64,
+        metrics: Arc<Metrics>,
+        recorder: Option<(Recorder, String)>,
+    ) -> Result<tokio::sync::mpsc::Receiver<Result<ComponentData, tonic::Status>>, tonic::Status>
+    {
+        let samples = replay.samples(id).ok_or_else(|| {
+            tonic::Status::not_found(format!("No recorded data for component {id}"))
+        })?;
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+
         tokio::spawn(async move {
+            metrics.stream_started();
+            'stream: loop {
+                for (delay, data) in &samples {
+                    tokio::time::sleep(*delay).await;
+                    let item = Ok(ComponentData {
+                        ts: Some(prost_types::Timestamp::from(SystemTime::now())),
+                        ..data.clone()
+                    });
+                    if let Ok(data) = &item {
+                        metrics.observe_sent(data);
+                    }
+                    if let Some((recorder, client)) = &recorder {
+                        recorder.sent(client, id, &item);
+                    }
+                    if let Err(err) = tx.send(item).await {
+                        log::debug!("stream_component_data(component_id={id}): {err}");
+                        break 'stream;
+                    }
+                }
+            }
+            metrics.stream_ended();
+        });
+        Ok(rx)
+    }
+
+    /// Applies the `rpc-faults` configured for `method`, returning
+    /// whether the call should be carried out, or an error status.
+    async fn inject_rpc_faults(
+        &self,
+        method: &str,
+        component_id: Option<u64>,
+    ) -> Result<bool, tonic::Status> {
+        let faults = self
+            .config
+            .rpc_faults(method, component_id)
+            .await
+            .map_err(|err| {
+                log::error!("Tulisp error:\n{}", err);
+                tonic::Status::internal(err.desc())
+            })?;
+        if !faults.delay().is_zero() {
+            tokio::time::sleep(faults.delay()).await;
+        }
+        match faults.outcome(method) {
+            RpcOutcome::Proceed => Ok(true),
+            RpcOutcome::Ignore => {
+                log::info!("{method}(component_id={component_id:?}): ignored by rpc-faults");
+                Ok(false)
+            }
+            RpcOutcome::Fail(status) => {
+                log::info!("{method}(component_id={component_id:?}): {status}");
+                Err(status)
+            }
+        }
+    }
+
+    fn start_timeout_tracker(
+        config: Config,
+        timeout_tracker: crate::timeout_tracker::TimeoutTracker,
+        sessions: Sessions,
+    ) -> AbortHandle {
+        let task = tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_millis(100)).await;
-                let expired_ids = timeout_tracker.remove_expired(config.retain_requests_duration());
-                for id in expired_ids {
-                    log::info!("Request timeout for component {}.", id);
-                    config.set_power_active(id, 0.0).unwrap();
+                if expire_requests(&config, &timeout_tracker, &sessions)
+                    .await
+                    .is_err()
+                {
+                    log::debug!("The simulation stopped, no more request timeouts");
+                    break;
                 }
             }
         });
+        task.abort_handle()
+    }
+}
+
+/// Applies the fallbacks of the power requests that are older than
+/// `retain-requests-duration-ms` of simulated time.  Fails when the
+/// simulation stopped.
+pub(crate) async fn expire_requests(
+    config: &Config,
+    timeout_tracker: &crate::timeout_tracker::TimeoutTracker,
+    sessions: &Sessions,
+) -> Result<(), tonic::Status> {
+    // Follow the policy of the current registry, which can change with
+    // a reload.
+    let registry = config.registry();
+    timeout_tracker.retain(|id| registry.expires_requests(id));
+    let now = config.simulation_time().await?;
+    let duration = config.retain_requests_duration().await?;
+    for (id, client) in timeout_tracker.remove_expired(now, duration) {
+        let Some(fallback) = registry.fallback(id) else {
+            continue;
+        };
+        sessions.release(id, &client);
+        // The request from another client is still in effect.
+        if timeout_tracker.is_pending(id) {
+            log::info!("Request timeout for component {id} from {client}, superseded.");
+            config.events().push(
+                "request-timeout",
+                Some(id),
+                json!({"client": client, "fallback": null, "error": null}),
+            );
+            continue;
+        }
+        log::info!("Request timeout for component {id} from {client}: {fallback}.");
+        let error = match config.apply_fallback(id, fallback).await {
+            Ok(()) => None,
+            Err(err) => {
+                log::error!("Tulisp error:\n{}", err);
+                Some(err.desc().to_string())
+            }
+        };
+        config.events().push(
+            "request-timeout",
+            Some(id),
+            json!({"client": client, "fallback": fallback.to_string(), "error": error}),
+        );
+    }
+    Ok(())
+}
+
+impl Drop for MicrogridServer {
+    fn drop(&mut self) {
+        self.timeout_tracker_task.abort();
+    }
+}
+
+/// Samples the data of a component at its stream interval, with the
+/// configured comm faults applied.
+///
+/// Samples are queued with the time they should be sent at, so that
+/// added latency doesn't delay the sampling schedule.  Sampling stops
+/// when the receiver is dropped.
+fn produce_component_data(
+    config: Config,
+    id: u64,
+) -> tokio::sync::mpsc::UnboundedReceiver<(
+    tokio::time::Instant,
+    Result<ComponentData, tonic::Status>,
+)> {
+    let (delayed_tx, delayed_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let stream_start = tokio::time::Instant::now();
+        let mut last_msg_ts = SystemTime::now();
+        let mut last_data: Option<ComponentData> = None;
+        loop {
+            // The component can be gone after a reload.
+            let (data, interval, comm_faults) = match config.get_component_data(id).await {
+                Ok(res) => res,
+                Err(status) => {
+                    let _ = delayed_tx.send((tokio::time::Instant::now(), Err(status)));
+                    break;
+                }
+            };
+
+            let send_at = tokio::time::Instant::now() + comm_faults.latency();
+            if let Some(status) = comm_faults.disconnect(stream_start.elapsed()) {
+                log::info!("stream_component_data(component_id={id}): {status}");
+                let _ = delayed_tx.send((send_at, Err(status)));
+                break;
+            }
+
+            let data = match last_data.take() {
+                Some(stale) if comm_faults.stale_sample() => stale,
+                _ => data,
+            };
+            last_data = Some(data.clone());
+
+            if !comm_faults.drop_sample() && delayed_tx.send((send_at, Ok(data))).is_err() {
+                break;
+            }
+
+            let now = SystemTime::now();
+            let tgt_ts = last_msg_ts + Duration::from_millis(interval);
+            let dur = Duration::from_millis(
+                tgt_ts.duration_since(now).unwrap_or_default().as_millis() as u64,
+            );
+            tokio::time::sleep(dur).await;
+            last_msg_ts = tgt_ts;
+        }
+    });
+
+    delayed_rx
+}
+
+/// The outcome of an RPC call, from whether it was carried out, for
+/// recordings.
+fn outcome_of(res: &Result<bool, tonic::Status>) -> RpcOutcome {
+    match res {
+        Ok(true) => RpcOutcome::Proceed,
+        Ok(false) => RpcOutcome::Ignore,
+        Err(status) => RpcOutcome::Fail(status.clone()),
+    }
+}
+
+impl MicrogridServer {
+    /// Records the latency and the result of a unary RPC.
+    fn observe_rpc<T>(&self, method: &str, start: Instant, res: &Result<T, tonic::Status>) {
+        let code = res
+            .as_ref()
+            .err()
+            .map_or(tonic::Code::Ok, |status| status.code());
+        self.config
+            .metrics()
+            .observe_rpc(method, code, start.elapsed());
+    }
+
+    /// Records an RPC call, when recording.
+    fn record_rpc(
+        &self,
+        method: &str,
+        client: Option<std::net::SocketAddr>,
+        params: serde_json::Value,
+        res: &Result<bool, tonic::Status>,
+    ) {
+        if let Some(recorder) = &self.recorder {
+            recorder.rpc(method, client, params, &outcome_of(res));
+        }
+    }
+}
+
+/// Command handlers
+impl MicrogridServer {
+    /// Checks a command against the client that controls the
+    /// component, and claims the component for the client.  The claim
+    /// has to be rolled back if the command fails.
+    async fn claim_controller(
+        &self,
+        method: &'static str,
+        id: u64,
+        client: &str,
+    ) -> Result<(Claim, Duration), tonic::Status> {
+        let now = self.config.simulation_time().await?;
+        let claim = self.sessions.check_and_claim(
+            id,
+            client,
+            method,
+            now,
+            self.config.retain_requests_duration().await?,
+            self.config.registry().single_controller(),
+        )?;
+        Ok((claim, now))
+    }
+
+    async fn handle_set_power_active(
+        &self,
+        request: &SetPowerActiveParam,
+        client: &str,
+    ) -> Result<bool, tonic::Status> {
+        if !self
+            .inject_rpc_faults("set-power-active", Some(request.component_id))
+            .await?
+        {
+            return Ok(false);
+        }
+        if !self.accept_command("set-power-active", request.component_id) {
+            return Ok(false);
+        }
+        let (claim, now) = self
+            .claim_controller("set-power-active", request.component_id, client)
+            .await?;
+        let res = self
+            .config
+            .set_power_active(request.component_id, request.power)
+            .await;
+
+        if let Err(err) = res {
+            log::error!("Tulisp error:\n{}", err);
+            self.sessions.rollback(claim);
+            return Err(tonic::Status::failed_precondition(err.desc()));
+        }
+        // Only requests that were applied can expire.
+        if self
+            .config
+            .registry()
+            .expires_requests(request.component_id)
+        {
+            self.timeout_tracker.add(request.component_id, client, now);
+        }
+        Ok(true)
+    }
+
+    /// Only the grid connection can be started and stopped, to simulate
+    /// grid outages.
+    fn check_grid(&self, method: &str, id: u64) -> Result<(), tonic::Status> {
+        match self.config.registry().category(id) {
+            Some(ComponentCategory::Grid) => Ok(()),
+            Some(_) => Err(tonic::Status::unimplemented(format!(
+                "{method} is only supported for the grid, not for component {id}"
+            ))),
+            None => Err(tonic::Status::not_found(format!(
+                "Component {id} not found"
+            ))),
+        }
+    }
+
+    async fn handle_start(&self, id: u64, client: &str) -> Result<bool, tonic::Status> {
+        if !self.inject_rpc_faults("start", Some(id)).await? || !self.accept_command("start", id) {
+            return Ok(false);
+        }
+        self.check_grid("start", id)?;
+        let (claim, _) = self.claim_controller("start", id, client).await?;
+        if let Err(err) = self.config.start_component(id).await {
+            log::error!("Tulisp error:\n{}", err);
+            self.sessions.rollback(claim);
+            return Err(tonic::Status::failed_precondition(err.desc()));
+        }
+        Ok(true)
+    }
+
+    async fn handle_stop(&self, id: u64, client: &str) -> Result<bool, tonic::Status> {
+        if !self.inject_rpc_faults("stop", Some(id)).await? || !self.accept_command("stop", id) {
+            return Ok(false);
+        }
+        self.check_grid("stop", id)?;
+        let (claim, _) = self.claim_controller("stop", id, client).await?;
+        if let Err(err) = self.config.stop_component(id).await {
+            log::error!("Tulisp error:\n{}", err);
+            self.sessions.rollback(claim);
+            return Err(tonic::Status::failed_precondition(err.desc()));
+        }
+        Ok(true)
     }
 }
 
@@ -70,42 +428,105 @@ impl Microgrid for MicrogridServer {
         &self,
         _request: tonic::Request<()>,
     ) -> std::result::Result<tonic::Response<MicrogridMetadata>, tonic::Status> {
-        let metadata = self.config.metadata().unwrap();
-        Ok(tonic::Response::new(metadata))
+        let start = Instant::now();
+        let res = match self.inject_rpc_faults("get-microgrid-metadata", None).await {
+            Ok(_) => self.config.metadata().await.map_err(Into::into),
+            Err(status) => Err(status),
+        };
+        self.observe_rpc("get-microgrid-metadata", start, &res);
+        res.map(tonic::Response::new)
     }
 
     async fn list_components(
         &self,
-        _request: tonic::Request<ComponentFilter>,
+        request: tonic::Request<ComponentFilter>,
     ) -> std::result::Result<tonic::Response<ComponentList>, tonic::Status> {
-        let components = self.config.components().unwrap();
-        Ok(tonic::Response::new(components))
+        let start = Instant::now();
+        let client = request.remote_addr();
+        let res = match self.inject_rpc_faults("list-components", None).await {
+            Ok(_) => Ok(match &self.replay {
+                Some(replay) => replay.components(),
+                None => self.config.components(),
+            }),
+            Err(status) => Err(status),
+        };
+        self.observe_rpc("list-components", start, &res);
+        self.record_rpc(
+            "list-components",
+            client,
+            json!({}),
+            &res.as_ref().map(|_| true).map_err(Clone::clone),
+        );
+        res.map(tonic::Response::new)
     }
     async fn list_connections(
         &self,
-        _request: tonic::Request<ConnectionFilter>,
+        request: tonic::Request<ConnectionFilter>,
     ) -> std::result::Result<tonic::Response<ConnectionList>, tonic::Status> {
-        let connections = self.config.connections().unwrap();
-        Ok(tonic::Response::new(connections))
+        let start = Instant::now();
+        let client = request.remote_addr();
+        let res = match self.inject_rpc_faults("list-connections", None).await {
+            Ok(_) => Ok(match &self.replay {
+                Some(replay) => replay.connections(),
+                None => self.config.connections(),
+            }),
+            Err(status) => Err(status),
+        };
+        self.observe_rpc("list-connections", start, &res);
+        self.record_rpc(
+            "list-connections",
+            client,
+            json!({}),
+            &res.as_ref().map(|_| true).map_err(Clone::clone),
+        );
+        res.map(tonic::Response::new)
     }
 
     async fn set_power_active(
         &self,
-        _request: tonic::Request<SetPowerActiveParam>,
+        request: tonic::Request<SetPowerActiveParam>,
     ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
-        let request = _request.into_inner();
-        if self.bat_inverter_ids.contains(&request.component_id) {
-            self.timeout_tracker.add(request.component_id);
-        }
-        let res = self
-            .config
-            .set_power_active(request.component_id, request.power);
+        let start = Instant::now();
+        let client = request.remote_addr();
+        let client_id = client_of(&request);
+        let request = request.into_inner();
+        let res = self.handle_set_power_active(&request, &client_id).await;
+        self.observe_rpc("set-power-active", start, &res);
+        self.record_rpc(
+            "set-power-active",
+            client,
+            json!({"component_id": request.component_id, "power": request.power}),
+            &res,
+        );
+        res.map(|_| tonic::Response::new(()))
+    }
 
-        if let Err(err) = res {
-            log::error!("Tulisp error:\n{}", err.format(&self.config.ctx.borrow()));
-            return Err(tonic::Status::failed_precondition(err.desc()));
-        }
-        Ok(tonic::Response::new(()))
+    async fn start(
+        &self,
+        request: tonic::Request<ComponentIdParam>,
+    ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+        let start = Instant::now();
+        let client = request.remote_addr();
+        let client_id = client_of(&request);
+        let id = request.into_inner().id;
+        let res = self.handle_start(id as u64, &client_id).await;
+        self.observe_rpc("start", start, &res);
+        self.record_rpc("start", client, json!({"component_id": id}), &res);
+        res.map(|_| tonic::Response::new(()))
+    }
+
+    async fn stop(
+        &self,
+        request: tonic::Request<ComponentIdParam>,
+    ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+        let start = Instant::now();
+        let client = request.remote_addr();
+        let client_id = client_of(&request);
+        let id = request.into_inner().id;
+        let res = self.handle_stop(id as u64, &client_id).await;
+        self.observe_rpc("stop", start, &res);
+        self.record_rpc("stop", client, json!({"component_id": id}), &res);
+        res.map(|_| tonic::Response::new(()))
     }
 
     type StreamComponentDataStream =
@@ -115,34 +536,47 @@ impl Microgrid for MicrogridServer {
         &self,
         request: tonic::Request<ComponentIdParam>,
     ) -> std::result::Result<tonic::Response<Self::StreamComponentDataStream>, tonic::Status> {
+        // The data sent to the client is recorded as sent, when
+        // recording.
+        let recorder = self
+            .recorder
+            .clone()
+            .map(|recorder| (recorder, client_of(&request)));
         let id = request.into_inner().id;
 
+        if let Some(replay) = &self.replay {
+            let rx = Self::stream_replay(replay, id, self.config.metrics(), recorder)?;
+            return Ok(tonic::Response::new(
+                Box::pin(ReceiverStream::new(rx)) as Self::StreamComponentDataStream
+            ));
+        }
+
+        if self.config.registry().stream_interval(id as u64).is_none() {
+            return Err(tonic::Status::not_found(format!(
+                "Component {id} not found, or has no data stream"
+            )));
+        }
+
         let (tx, rx) = tokio::sync::mpsc::channel(128);
-        let config = self.config.clone();
 
+        let mut delayed_rx = produce_component_data(self.config.clone(), id as u64);
+        let metrics = self.config.metrics();
         tokio::spawn(async move {
-            let mut last_msg_ts = SystemTime::now();
-            loop {
-                let (data, interval) = config
-                    .get_component_data(id as u64)
-                    .map_err(|e| {
-                        log::error!("Tulisp error:\n{}", e.format(&config.ctx.borrow()));
-                        e
-                    })
-                    .unwrap();
-
-                if let Err(err) = tx.send(Result::<_, tonic::Status>::Ok(data)).await {
+            metrics.stream_started();
+            while let Some((send_at, item)) = delayed_rx.recv().await {
+                tokio::time::sleep_until(send_at).await;
+                if let Ok(data) = &item {
+                    metrics.observe_sent(data);
+                }
+                if let Some((recorder, client)) = &recorder {
+                    recorder.sent(client, id, &item);
+                }
+                if let Err(err) = tx.send(item).await {
                     log::debug!("stream_component_data(component_id={id}): {err}");
                     break;
                 }
-
-                let now = SystemTime::now();
-                let tgt_ts = last_msg_ts + Duration::from_millis(interval as u64);
-                let dur =
-                    Duration::from_millis(tgt_ts.duration_since(now).unwrap().as_millis() as u64);
-                tokio::time::sleep(dur).await;
-                last_msg_ts = tgt_ts;
             }
+            metrics.stream_ended();
         });
 
         let output_stream = ReceiverStream::new(rx);
@@ -180,12 +614,6 @@ impl Microgrid for MicrogridServer {
     ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
         todo!()
     }
-    async fn start(
-        &self,
-        _request: tonic::Request<ComponentIdParam>,
-    ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
-        todo!()
-    }
     async fn hot_standby(
         &self,
         _request: tonic::Request<ComponentIdParam>,
@@ -198,12 +626,6 @@ impl Microgrid for MicrogridServer {
     ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
         todo!()
     }
-    async fn stop(
-        &self,
-        _request: tonic::Request<ComponentIdParam>,
-    ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
-        todo!()
-    }
     async fn error_ack(
         &self,
         _request: tonic::Request<ComponentIdParam>,
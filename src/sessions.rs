@@ -0,0 +1,320 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use crate::events::Events;
+
+/// Identifies the client of a request, by its `x-client-id` metadata,
+/// or else by its address, which changes when it reconnects.
+pub(crate) fn client_of<T>(request: &tonic::Request<T>) -> String {
+    if let Some(id) = request
+        .metadata()
+        .get("x-client-id")
+        .and_then(|id| id.to_str().ok())
+    {
+        return id.to_string();
+    }
+    request
+        .remote_addr()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.to_string())
+}
+
+struct Controller {
+    client: String,
+    method: &'static str,
+    /// The simulated time of the command.
+    at: Duration,
+    released: bool,
+    /// Identifies the claim of the command, for rolling it back.
+    claim: u64,
+}
+
+impl Controller {
+    fn is_active(&self, now: Duration, duration: Duration) -> bool {
+        !self.released && now.saturating_sub(self.at) < duration
+    }
+}
+
+/// A client's claim on a component, from a command that is being
+/// applied.
+pub(crate) struct Claim {
+    component_id: u64,
+    id: u64,
+    previous: Option<Controller>,
+}
+
+/// Tracks which client controls each component.  A client controls a
+/// component from its last successful command, until
+/// `retain-requests-duration-ms` of simulated time after it, or until
+/// its request expires.
+#[derive(Clone)]
+pub(crate) struct Sessions {
+    controllers: Arc<Mutex<HashMap<u64, Controller>>>,
+    next_claim: Arc<AtomicU64>,
+    /// Where conflicts are reported.
+    events: Arc<Events>,
+}
+
+impl Sessions {
+    pub(crate) fn new(events: Arc<Events>) -> Self {
+        Self {
+            controllers: Arc::default(),
+            next_claim: Arc::default(),
+            events,
+        }
+    }
+
+    /// Checks a command from `client` against the component's current
+    /// controller, at the simulated time `now`, and records `client` as
+    /// its controller, under the same lock, so that concurrent commands
+    /// can't both pass the check.  A command from another client is a
+    /// conflict, which is logged and reported as an event, and rejected
+    /// when `single_controller` is set.
+    ///
+    /// The returned claim has to be rolled back if the command fails.
+    pub(crate) fn check_and_claim(
+        &self,
+        component_id: u64,
+        client: &str,
+        method: &'static str,
+        now: Duration,
+        duration: Duration,
+        single_controller: bool,
+    ) -> Result<Claim, tonic::Status> {
+        let mut controllers = self.controllers.lock().unwrap();
+        if let Some(controller) = controllers
+            .get(&component_id)
+            .filter(|c| c.client != client && c.is_active(now, duration))
+        {
+            log::warn!(
+                "{method}(component_id={component_id}) from {client} conflicts with {}() from {}{}",
+                controller.method,
+                controller.client,
+                if single_controller { ", rejected" } else { "" },
+            );
+            self.events.push(
+                "command-conflict",
+                Some(component_id),
+                json!({
+                    "client": client,
+                    "method": method,
+                    "controller": controller.client,
+                    "controller_method": controller.method,
+                    "rejected": single_controller,
+                }),
+            );
+            if single_controller {
+                return Err(tonic::Status::failed_precondition(format!(
+                    "Component {component_id} is controlled by {}",
+                    controller.client
+                )));
+            }
+        }
+
+        let id = self.next_claim.fetch_add(1, Ordering::Relaxed);
+        let previous = controllers.insert(
+            component_id,
+            Controller {
+                client: client.to_string(),
+                method,
+                at: now,
+                released: false,
+                claim: id,
+            },
+        );
+        Ok(Claim {
+            component_id,
+            id,
+            previous,
+        })
+    }
+
+    /// Restores the controller from before a failed command, unless
+    /// another command claimed the component since.
+    pub(crate) fn rollback(&self, claim: Claim) {
+        let mut controllers = self.controllers.lock().unwrap();
+        if controllers
+            .get(&claim.component_id)
+            .is_none_or(|current| current.claim != claim.id)
+        {
+            return;
+        }
+        match claim.previous {
+            Some(previous) => controllers.insert(claim.component_id, previous),
+            None => controllers.remove(&claim.component_id),
+        };
+    }
+
+    /// Ends the control of the component by `client`, like when its
+    /// request expires.  Its last controller is still reported.
+    pub(crate) fn release(&self, component_id: u64, client: &str) {
+        if let Some(controller) = self
+            .controllers
+            .lock()
+            .unwrap()
+            .get_mut(&component_id)
+            .filter(|c| c.client == client)
+        {
+            controller.released = true;
+        }
+    }
+
+    /// Returns the client that last commanded each component, and
+    /// whether it still controls it at the simulated time `now`.
+    pub(crate) fn to_json(&self, now: Duration, duration: Duration) -> Value {
+        let controllers = self.controllers.lock().unwrap();
+        let mut ids: Vec<_> = controllers.keys().copied().collect();
+        ids.sort();
+        Value::Array(
+            ids.into_iter()
+                .map(|id| {
+                    let controller = &controllers[&id];
+                    json!({
+                        "component_id": id,
+                        "client": controller.client,
+                        "method": controller.method,
+                        "age_ms": now.saturating_sub(controller.at).as_millis() as u64,
+                        "active": controller.is_active(now, duration),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tonic::Code;
+
+    use super::Sessions;
+    use crate::events::Events;
+
+    const RETAIN: Duration = Duration::from_secs(60);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Claims a component with a `set-power-active` command at `now`
+    /// seconds of simulated time.
+    fn claim(
+        sessions: &Sessions,
+        id: u64,
+        client: &str,
+        now: u64,
+        single_controller: bool,
+    ) -> Result<(), Code> {
+        sessions
+            .check_and_claim(
+                id,
+                client,
+                "set-power-active",
+                secs(now),
+                RETAIN,
+                single_controller,
+            )
+            .map(|_| ())
+            .map_err(|status| status.code())
+    }
+
+    #[test]
+    fn conflicts_are_reported() {
+        let events = Arc::new(Events::default());
+        let sessions = Sessions::new(events.clone());
+        assert_eq!(claim(&sessions, 3, "a", 0, false), Ok(()));
+
+        // The controller itself, and other components, don't conflict.
+        assert_eq!(claim(&sessions, 3, "a", 1, false), Ok(()));
+        assert_eq!(claim(&sessions, 4, "b", 1, false), Ok(()));
+        assert_eq!(events.since(0).as_array().unwrap().len(), 0);
+
+        assert_eq!(claim(&sessions, 3, "b", 1, false), Ok(()));
+        let reported = events.since(0);
+        let reported = reported.as_array().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0]["kind"], "command-conflict");
+        assert_eq!(reported[0]["details"]["controller"], "a");
+        assert_eq!(reported[0]["details"]["rejected"], false);
+
+        // Without `single_controller`, the new client takes over.
+        assert_eq!(sessions.to_json(secs(1), RETAIN)[0]["client"], "b");
+    }
+
+    #[test]
+    fn single_controller_locks_until_expiry() {
+        let sessions = Sessions::new(Arc::new(Events::default()));
+        assert_eq!(claim(&sessions, 3, "a", 10, true), Ok(()));
+
+        assert_eq!(
+            claim(&sessions, 3, "b", 69, true),
+            Err(Code::FailedPrecondition)
+        );
+        let controllers = sessions.to_json(secs(69), RETAIN);
+        assert_eq!(controllers[0]["client"], "a");
+        assert_eq!(controllers[0]["age_ms"], 59000);
+
+        // After `retain-requests-duration-ms` of simulated time.
+        let controllers = sessions.to_json(secs(70), RETAIN);
+        assert_eq!(controllers[0]["active"], false);
+        assert_eq!(claim(&sessions, 3, "b", 70, true), Ok(()));
+    }
+
+    #[test]
+    fn released_components_are_unlocked() {
+        let sessions = Sessions::new(Arc::new(Events::default()));
+        assert_eq!(claim(&sessions, 3, "a", 0, true), Ok(()));
+        // Only the controller's own requests release it.
+        sessions.release(3, "b");
+        assert_eq!(
+            claim(&sessions, 3, "b", 1, true),
+            Err(Code::FailedPrecondition)
+        );
+        sessions.release(3, "a");
+
+        let controllers = sessions.to_json(secs(1), RETAIN);
+        assert_eq!(controllers[0]["client"], "a");
+        assert_eq!(controllers[0]["active"], false);
+
+        // A new command takes control again.
+        assert_eq!(claim(&sessions, 3, "b", 1, true), Ok(()));
+        assert_eq!(
+            claim(&sessions, 3, "a", 2, true),
+            Err(Code::FailedPrecondition)
+        );
+    }
+
+    #[test]
+    fn failed_commands_are_rolled_back() {
+        let sessions = Sessions::new(Arc::new(Events::default()));
+        let first = sessions
+            .check_and_claim(3, "a", "set-power-active", secs(0), RETAIN, true)
+            .unwrap();
+        sessions.rollback(first);
+        assert_eq!(sessions.to_json(secs(0), RETAIN), serde_json::json!([]));
+
+        assert_eq!(claim(&sessions, 3, "a", 1, true), Ok(()));
+        let failed = sessions
+            .check_and_claim(3, "a", "set-power-active", secs(2), RETAIN, true)
+            .unwrap();
+        // A later claim isn't undone by the rollback of an earlier one.
+        let stale = sessions
+            .check_and_claim(4, "a", "set-power-active", secs(2), RETAIN, true)
+            .unwrap();
+        assert_eq!(claim(&sessions, 4, "a", 3, true), Ok(()));
+        sessions.rollback(stale);
+        sessions.rollback(failed);
+
+        let controllers = sessions.to_json(secs(3), RETAIN);
+        assert_eq!(controllers[0]["age_ms"], 2000);
+        assert_eq!(controllers[1]["age_ms"], 0);
+    }
+}
@@ -1,47 +1,113 @@
 use std::{
-    cell::RefCell,
-    collections::{HashMap, HashSet},
-    rc::Rc,
+    collections::HashMap,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use std::time::Instant;
-
+/// Tracks when each client last made a power request to each
+/// component, in simulated time, so that requests expire the same way
+/// when time is stepped manually.  The requests of each client expire
+/// on their own, so that another client's commands don't renew them.
 #[derive(Clone, Default)]
 pub(crate) struct TimeoutTracker {
-    data: Rc<RefCell<HashMap<u64, Instant>>>,
+    data: Arc<Mutex<HashMap<(u64, String), Duration>>>,
 }
 
-// Tokio is configured to use the current_thread runtime, so it is not unsafe to
-// make this Send and Sync.
-unsafe impl Send for TimeoutTracker {}
-unsafe impl Sync for TimeoutTracker {}
-
 impl TimeoutTracker {
     pub(crate) fn new() -> Self {
         Self {
-            data: Rc::new(RefCell::new(HashMap::new())),
+            data: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Notes a request from `client` to the component, at the
+    /// simulated time `now`.
+    pub(crate) fn add(&self, id: u64, client: &str, now: Duration) {
+        self.data
+            .lock()
+            .unwrap()
+            .insert((id, client.to_string()), now);
+    }
+
+    /// Returns whether any client has a pending request to the
+    /// component.
+    pub(crate) fn is_pending(&self, id: u64) -> bool {
+        self.data.lock().unwrap().keys().any(|(key, _)| *key == id)
+    }
+
+    /// Returns the components with pending requests, and the time left
+    /// until their last request expires.
+    pub(crate) fn pending(&self, now: Duration, duration: Duration) -> Vec<(u64, Duration)> {
+        let mut pending: HashMap<u64, Duration> = HashMap::new();
+        for ((id, _), &at) in self.data.lock().unwrap().iter() {
+            let left = (at + duration).saturating_sub(now);
+            let entry = pending.entry(*id).or_default();
+            *entry = (*entry).max(left);
         }
+        let mut pending: Vec<_> = pending.into_iter().collect();
+        pending.sort();
+        pending
     }
 
-    pub(crate) fn add(&self, id: u64) {
-        let now = Instant::now();
-        self.data.borrow_mut().insert(id, now);
+    /// Stops tracking the components that `keep` returns false for.
+    pub(crate) fn retain(&self, keep: impl Fn(u64) -> bool) {
+        self.data.lock().unwrap().retain(|(id, _), _| keep(*id));
     }
 
-    pub(crate) fn remove_expired(&self, duration: Duration) -> HashSet<u64> {
-        let now = Instant::now();
-        let mut expired_ids = HashSet::new();
+    /// Stops tracking the requests that are at least `duration` old at
+    /// the simulated time `now`, and returns their components and
+    /// clients, sorted.
+    pub(crate) fn remove_expired(&self, now: Duration, duration: Duration) -> Vec<(u64, String)> {
+        let mut expired = Vec::new();
 
-        self.data.borrow_mut().retain(|&id, instant| {
-            if *instant <= now - duration {
-                expired_ids.insert(id);
+        self.data.lock().unwrap().retain(|key, &mut at| {
+            if at + duration <= now {
+                expired.push(key.clone());
                 false
             } else {
                 true
             }
         });
 
-        expired_ids
+        expired.sort();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TimeoutTracker;
+
+    const RETAIN: Duration = Duration::from_secs(60);
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn requests_from_each_client_expire_on_their_own() {
+        let tracker = TimeoutTracker::new();
+        tracker.add(3, "a", secs(0));
+        tracker.add(3, "b", secs(20));
+        tracker.add(3, "a", secs(30));
+        tracker.add(3, "b", secs(40));
+
+        // The commands from "b" don't renew the last request from "a".
+        assert!(tracker.remove_expired(secs(89), RETAIN).is_empty());
+        assert_eq!(tracker.pending(secs(89), RETAIN), [(3, secs(11))]);
+        assert_eq!(
+            tracker.remove_expired(secs(90), RETAIN),
+            [(3, "a".to_string())]
+        );
+        assert!(tracker.is_pending(3));
+
+        assert_eq!(
+            tracker.remove_expired(secs(100), RETAIN),
+            [(3, "b".to_string())]
+        );
+        assert!(!tracker.is_pending(3));
+        assert!(tracker.pending(secs(100), RETAIN).is_empty());
     }
 }
@@ -0,0 +1,232 @@
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::{
+    proto::microgrid::{component, Component},
+    registry::Registry,
+};
+
+struct Node {
+    id: u64,
+    name: String,
+    category: String,
+    type_: Option<String>,
+    hidden: bool,
+}
+
+struct Edge {
+    start: u64,
+    end: u64,
+    hidden: bool,
+}
+
+/// The component graph built by the config, including the hidden
+/// components, for exporting as DOT or JSON.
+///
+/// Components and connections are sorted, so that exports of different
+/// config versions can be diffed.
+pub(crate) struct Topology {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+fn enum_name(name: &str, prefix: &str) -> String {
+    name.trim_start_matches(prefix)
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+/// Escapes text for a quoted DOT string.
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn component_type(component: &Component) -> Option<String> {
+    let name = match component.metadata.as_ref()? {
+        component::Metadata::Inverter(inverter) => {
+            enum_name(inverter.r#type().as_str_name(), "INVERTER_TYPE_")
+        }
+        component::Metadata::Battery(battery) => {
+            enum_name(battery.r#type().as_str_name(), "BATTERY_TYPE_")
+        }
+        component::Metadata::EvCharger(ev_charger) => {
+            enum_name(ev_charger.r#type().as_str_name(), "EV_CHARGER_TYPE_")
+        }
+        _ => return None,
+    };
+    Some(name)
+}
+
+impl Topology {
+    pub(crate) fn from_registry(registry: &Registry) -> Self {
+        let mut nodes = Vec::new();
+        for (list, hidden) in [
+            (registry.components(), false),
+            (registry.hidden_components(), true),
+        ] {
+            nodes.extend(list.components.iter().map(|c| Node {
+                id: c.id,
+                name: c.name.clone(),
+                category: enum_name(c.category().as_str_name(), "COMPONENT_CATEGORY_"),
+                type_: component_type(c),
+                hidden,
+            }));
+        }
+        nodes.sort_by_key(|node| node.id);
+
+        let mut edges = Vec::new();
+        for (list, hidden) in [
+            (registry.connections(), false),
+            (registry.hidden_connections(), true),
+        ] {
+            edges.extend(list.connections.iter().map(|c| Edge {
+                start: c.start,
+                end: c.end,
+                hidden,
+            }));
+        }
+        edges.sort_by_key(|edge| (edge.start, edge.end));
+
+        Self { nodes, edges }
+    }
+
+    pub(crate) fn to_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = self.write_dot(&mut out);
+        out
+    }
+
+    fn write_dot(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "digraph microgrid {{")?;
+        writeln!(out, "  node [shape=box];")?;
+        for node in &self.nodes {
+            let mut label = format!(
+                "{} {}\\n{}",
+                escape_dot(&node.category),
+                node.id,
+                escape_dot(&node.name)
+            );
+            if let Some(type_) = &node.type_ {
+                write!(label, "\\n{}", escape_dot(type_))?;
+            }
+            if node.hidden {
+                label.push_str("\\n(hidden)");
+            }
+            let style = if node.hidden { ", style=dashed" } else { "" };
+            writeln!(out, "  {} [label=\"{label}\"{style}];", node.id)?;
+        }
+        for edge in &self.edges {
+            let style = if edge.hidden { " [style=dashed]" } else { "" };
+            writeln!(out, "  {} -> {}{style};", edge.start, edge.end)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let components: Vec<Value> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                json!({
+                    "id": node.id,
+                    "name": node.name,
+                    "category": node.category,
+                    "type": node.type_,
+                    "hidden": node.hidden,
+                })
+            })
+            .collect();
+        let connections: Vec<Value> = self
+            .edges
+            .iter()
+            .map(|edge| {
+                json!({
+                    "start": edge.start,
+                    "end": edge.end,
+                    "hidden": edge.hidden,
+                })
+            })
+            .collect();
+        json!({ "components": components, "connections": connections })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology() -> Topology {
+        let node = |id, name: &str, category: &str, type_: Option<&str>, hidden| Node {
+            id,
+            name: name.to_string(),
+            category: category.to_string(),
+            type_: type_.map(str::to_string),
+            hidden,
+        };
+        Topology {
+            nodes: vec![
+                node(1, "grid", "grid", None, false),
+                node(2, r#"main "meter" \ A"#, "meter", None, false),
+                node(3, "inv-3", "inverter", Some("battery"), false),
+                node(4, "consumer", "meter", None, true),
+            ],
+            edges: vec![
+                Edge {
+                    start: 1,
+                    end: 2,
+                    hidden: false,
+                },
+                Edge {
+                    start: 2,
+                    end: 3,
+                    hidden: false,
+                },
+                Edge {
+                    start: 2,
+                    end: 4,
+                    hidden: true,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn exports_dot() {
+        assert_eq!(
+            topology().to_dot(),
+            r#"digraph microgrid {
+  node [shape=box];
+  1 [label="grid 1\ngrid"];
+  2 [label="meter 2\nmain \"meter\" \\ A"];
+  3 [label="inverter 3\ninv-3\nbattery"];
+  4 [label="meter 4\nconsumer\n(hidden)", style=dashed];
+  1 -> 2;
+  2 -> 3;
+  2 -> 4 [style=dashed];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn exports_json() {
+        let json = topology().to_json();
+        assert_eq!(
+            json["components"][1],
+            json!({
+                "id": 2,
+                "name": r#"main "meter" \ A"#,
+                "category": "meter",
+                "type": null,
+                "hidden": false,
+            })
+        );
+        assert_eq!(json["components"][2]["type"], "battery");
+        assert_eq!(json["components"][3]["hidden"], true);
+        assert_eq!(
+            json["connections"][2],
+            json!({ "start": 2, "end": 4, "hidden": true })
+        );
+    }
+}
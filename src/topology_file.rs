@@ -0,0 +1,453 @@
+use std::fmt::Write;
+
+use serde_json::{Map, Value};
+
+/// Component kinds, with their lisp constructor and the keys they
+/// accept, besides `kind` and `successors`.
+const KINDS: &[(&str, &str, &[&str])] = &[
+    ("grid", "make-grid", &["id", "rated-fuse-current"]),
+    (
+        "meter",
+        "make-meter",
+        &["id", "interval", "comm-faults", "config", "power", "hidden"],
+    ),
+    (
+        "battery-inverter",
+        "make-battery-inverter",
+        &["id", "interval", "comm-faults", "config"],
+    ),
+    (
+        "battery",
+        "make-battery",
+        &["id", "interval", "comm-faults", "config"],
+    ),
+    (
+        "solar-inverter",
+        "make-solar-inverter",
+        &[
+            "id",
+            "interval",
+            "comm-faults",
+            "config",
+            "sunlight%",
+            "profile",
+            "cloud-cover",
+            "cloud-variability",
+        ],
+    ),
+    (
+        "ev-charger",
+        "make-ev-charger",
+        &["id", "interval", "comm-faults", "config", "sessions"],
+    ),
+    ("chp", "make-chp", &["id"]),
+];
+
+/// Sections of `defaults`, with the lisp variables they override.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("battery", "battery-defaults"),
+    ("battery-inverter", "battery-inverter-defaults"),
+    ("solar-inverter", "solar-inverter-defaults"),
+    ("meter", "meter-defaults"),
+    ("ev-charger", "ev-charger-defaults"),
+    ("ev-session", "ev-session-defaults"),
+];
+
+/// The formats of declarative topology files, from their extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Returns the format of a config file, or `None` for lisp.
+    pub(crate) fn of(filename: &str) -> Option<Self> {
+        match filename.rsplit_once('.')?.1 {
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn parse(self, source: &str) -> Result<Value, String> {
+        match self {
+            Self::Json => serde_json::from_str(source).map_err(|e| e.to_string()),
+            Self::Toml => toml::from_str(source).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yaml::from_str(source).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<&str, String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_%".contains(c));
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("Invalid name: {name:?}"))
+    }
+}
+
+fn write_number(out: &mut String, number: &serde_json::Number) {
+    match number.as_i64() {
+        Some(int) => write!(out, "{int}"),
+        None => write!(out, "{:?}", number.as_f64().unwrap_or_default()),
+    }
+    .unwrap();
+}
+
+/// Writes a value as quoted lisp data: objects become alists, arrays
+/// become lists, and strings become symbols.
+fn write_datum(out: &mut String, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Null | Value::Bool(false) => out.push_str("nil"),
+        Value::Bool(true) => out.push('t'),
+        Value::Number(number) => write_number(out, number),
+        Value::String(name) => out.push_str(check_name(name)?),
+        Value::Array(items) => {
+            out.push('(');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    out.push(' ');
+                }
+                write_datum(out, item)?;
+            }
+            out.push(')');
+        }
+        Value::Object(entries) => {
+            out.push('(');
+            for (idx, (key, item)) in entries.iter().enumerate() {
+                if idx > 0 {
+                    out.push(' ');
+                }
+                write!(out, "({} . ", check_name(key)?).unwrap();
+                write_datum(out, item)?;
+                out.push(')');
+            }
+            out.push(')');
+        }
+    }
+    Ok(())
+}
+
+fn write_quoted(out: &mut String, value: &Value) -> Result<(), String> {
+    if matches!(value, Value::String(_) | Value::Array(_) | Value::Object(_)) {
+        out.push('\'');
+    }
+    write_datum(out, value)
+}
+
+/// Writes a setting's value, where strings are lisp strings, like
+/// `socket-addr`.
+fn write_setting(out: &mut String, value: &Value) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            write!(
+                out,
+                "\"{}\"",
+                text.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+            .unwrap();
+            Ok(())
+        }
+        _ => write_quoted(out, value),
+    }
+}
+
+fn as_object<'a>(value: &'a Value, what: &str) -> Result<&'a Map<String, Value>, String> {
+    value
+        .as_object()
+        .ok_or_else(|| format!("`{what}` must be an object"))
+}
+
+fn write_component(
+    out: &mut String,
+    ids: &mut Vec<i64>,
+    value: &Value,
+    depth: usize,
+) -> Result<(), String> {
+    let component = as_object(value, "component")?;
+    let kind = component
+        .get("kind")
+        .and_then(Value::as_str)
+        .ok_or("Missing `kind` in component")?;
+    let Some((_, constructor, keys)) = KINDS.iter().find(|(name, _, _)| *name == kind) else {
+        return Err(format!("Unknown component kind: {kind}"));
+    };
+    if let Some(id) = component.get("id") {
+        let id = id.as_i64().ok_or("`id` must be an integer")?;
+        if ids.contains(&id) {
+            return Err(format!("Duplicate component id: {id}"));
+        }
+        ids.push(id);
+    }
+    let indent = " ".repeat(depth * 2);
+
+    write!(out, "{indent}({constructor}").unwrap();
+    for (key, item) in component {
+        if key == "kind" || key == "successors" {
+            continue;
+        }
+        if !keys.contains(&key.as_str()) {
+            return Err(format!("Unknown key for {kind}: {key}"));
+        }
+        write!(out, "\n{indent}  :{key} ").unwrap();
+        write_quoted(out, item)?;
+    }
+    if let Some(successors) = component.get("successors") {
+        if ["battery", "ev-charger", "chp"].contains(&kind) {
+            return Err(format!("A {kind} can't have successors"));
+        }
+        let successors = successors
+            .as_array()
+            .ok_or("`successors` must be an array")?;
+        write!(out, "\n{indent}  :successors (list").unwrap();
+        for successor in successors {
+            out.push('\n');
+            write_component(out, ids, successor, depth + 2)?;
+        }
+        out.push(')');
+    }
+    out.push(')');
+    Ok(())
+}
+
+/// Translates a declarative topology file into a lisp config, which
+/// builds the same components with the lisp constructors.
+///
+/// The file is a JSON, TOML or YAML object, with these optional keys:
+///
+///   - `settings`: variables to set, like `socket-addr` or
+///     `meter-interval`.
+///   - `metadata`: the microgrid metadata, like in `config.lisp`.
+///   - `defaults`: overrides for the component defaults, per kind.
+///   - `grid`: the component tree, starting with the grid connection.
+///     Each component has a `kind`, its constructor's keys, and
+///     optionally a list of `successors`.
+///   - `connections`: extra connections between components, as pairs
+///     of component IDs, like `[[2, 1005]]`.  The components need
+///     explicit IDs.
+///
+/// The translated config expects the simulator's lisp files and the
+/// defaults from `sim/defaults.lisp` to be evaluated before it.
+pub(crate) fn to_lisp(source: &str, format: Format) -> Result<String, String> {
+    let file = format.parse(source)?;
+    let file = as_object(&file, "topology")?;
+
+    let mut out = String::new();
+
+    for key in file.keys() {
+        if !["settings", "metadata", "defaults", "grid", "connections"].contains(&key.as_str()) {
+            return Err(format!("Unknown key: {key}"));
+        }
+    }
+
+    if let Some(settings) = file.get("settings") {
+        for (name, value) in as_object(settings, "settings")? {
+            write!(out, "(setq {} ", check_name(name)?).unwrap();
+            write_setting(&mut out, value)?;
+            out.push_str(")\n");
+        }
+    }
+
+    if let Some(metadata) = file.get("metadata") {
+        out.push_str("(setq metadata ");
+        write_quoted(&mut out, metadata)?;
+        out.push_str(")\n");
+    }
+
+    if let Some(defaults) = file.get("defaults") {
+        for (kind, overrides) in as_object(defaults, "defaults")? {
+            let Some((_, var)) = DEFAULTS.iter().find(|(name, _)| name == kind) else {
+                return Err(format!("Unknown defaults: {kind}"));
+            };
+            as_object(overrides, kind)?;
+            // Earlier entries of an alist take precedence.
+            write!(out, "(setq {var} (append ").unwrap();
+            write_quoted(&mut out, overrides)?;
+            writeln!(out, " {var}))").unwrap();
+        }
+    }
+
+    let mut ids = Vec::new();
+    if let Some(grid) = file.get("grid") {
+        write_component(&mut out, &mut ids, grid, 0)?;
+        out.push('\n');
+    }
+
+    if let Some(connections) = file.get("connections") {
+        let connections = connections
+            .as_array()
+            .ok_or("`connections` must be an array")?;
+        for connection in connections {
+            let pair = connection
+                .as_array()
+                .and_then(|pair| pair.iter().map(Value::as_i64).collect::<Option<Vec<_>>>())
+                .filter(|pair| pair.len() == 2)
+                .ok_or("Each connection must be a pair of component IDs")?;
+            if let Some(id) = pair.iter().find(|id| !ids.contains(id)) {
+                return Err(format!("Unknown component id in connection: {id}"));
+            }
+            writeln!(out, "(add-to-connections-alist {} {})", pair[0], pair[1]).unwrap();
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_lisp, Format};
+
+    /// The translation of the topology in each format below.
+    const LISP: &str = r#"(setq meter-interval 100)
+(setq socket-addr "[::1]:8801")
+(setq battery-defaults (append '((capacity . 50000.0)) battery-defaults))
+(make-grid
+  :id 1
+  :successors (list
+    (make-meter
+      :id 2
+      :power 1000.0)
+    (make-meter
+      :id 3
+      :interval 500)))
+(add-to-connections-alist 2 3)
+"#;
+
+    #[test]
+    fn translates_json() {
+        let lisp = to_lisp(
+            r#"{
+                "settings": { "meter-interval": 100, "socket-addr": "[::1]:8801" },
+                "defaults": { "battery": { "capacity": 50000.0 } },
+                "grid": {
+                    "kind": "grid",
+                    "id": 1,
+                    "successors": [
+                        { "kind": "meter", "id": 2, "power": 1000.0 },
+                        { "kind": "meter", "id": 3, "interval": 500 }
+                    ]
+                },
+                "connections": [[2, 3]]
+            }"#,
+            Format::Json,
+        );
+        assert_eq!(lisp.unwrap(), LISP);
+    }
+
+    #[test]
+    fn translates_toml() {
+        let lisp = to_lisp(
+            r#"
+connections = [[2, 3]]
+
+[settings]
+meter-interval = 100
+socket-addr = "[::1]:8801"
+
+[defaults.battery]
+capacity = 50000.0
+
+[grid]
+kind = "grid"
+id = 1
+
+[[grid.successors]]
+kind = "meter"
+id = 2
+power = 1000.0
+
+[[grid.successors]]
+kind = "meter"
+id = 3
+interval = 500
+"#,
+            Format::Toml,
+        );
+        assert_eq!(lisp.unwrap(), LISP);
+    }
+
+    #[test]
+    fn translates_yaml() {
+        let lisp = to_lisp(
+            r#"
+settings:
+  meter-interval: 100
+  socket-addr: "[::1]:8801"
+defaults:
+  battery:
+    capacity: 50000.0
+grid:
+  kind: grid
+  id: 1
+  successors:
+    - kind: meter
+      id: 2
+      power: 1000.0
+    - kind: meter
+      id: 3
+      interval: 500
+connections:
+  - [2, 3]
+"#,
+            Format::Yaml,
+        );
+        assert_eq!(lisp.unwrap(), LISP);
+    }
+
+    #[test]
+    fn detects_formats() {
+        assert_eq!(Format::of("site.json"), Some(Format::Json));
+        assert_eq!(Format::of("site.toml"), Some(Format::Toml));
+        assert_eq!(Format::of("site.yml"), Some(Format::Yaml));
+        assert_eq!(Format::of("config.lisp"), None);
+    }
+
+    #[test]
+    fn rejects_unknown_category() {
+        let err = to_lisp(
+            r#"{ "grid": { "kind": "wind-turbine", "id": 1 } }"#,
+            Format::Json,
+        )
+        .unwrap_err();
+        assert_eq!(err, "Unknown component kind: wind-turbine");
+
+        let err = to_lisp(r#"{ "defaults": { "wind-turbine": {} } }"#, Format::Json).unwrap_err();
+        assert_eq!(err, "Unknown defaults: wind-turbine");
+    }
+
+    #[test]
+    fn rejects_dangling_connection_id() {
+        let err = to_lisp(
+            r#"{
+                "grid": { "kind": "grid", "id": 1, "successors": [{ "kind": "meter", "id": 2 }] },
+                "connections": [[2, 7]]
+            }"#,
+            Format::Json,
+        )
+        .unwrap_err();
+        assert_eq!(err, "Unknown component id in connection: 7");
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let err = to_lisp(
+            r#"{
+                "grid": {
+                    "kind": "grid",
+                    "id": 1,
+                    "successors": [{ "kind": "meter", "id": 2 }, { "kind": "meter", "id": 2 }]
+                }
+            }"#,
+            Format::Json,
+        )
+        .unwrap_err();
+        assert_eq!(err, "Duplicate component id: 2");
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem},
+    Frame,
+};
+
+use crate::{
+    config::Config,
+    metrics::component_values,
+    proto::{
+        common::components::ComponentCategory,
+        microgrid::{component_data, ComponentData},
+    },
+    timeout_tracker::TimeoutTracker,
+};
+
+/// Components that received a command within this duration are
+/// highlighted.
+const RECENT_COMMAND: Duration = Duration::from_secs(5);
+
+struct Node {
+    depth: usize,
+    id: u64,
+    category: String,
+    values: Vec<(&'static str, f32)>,
+    state: Option<String>,
+    recent_command: bool,
+}
+
+/// A snapshot of the simulation, for one frame of the dashboard.
+struct View {
+    nodes: Vec<Node>,
+    pending: Vec<(u64, Duration)>,
+}
+
+fn strip_prefix(name: &str, prefix: &str) -> String {
+    name.strip_prefix(prefix)
+        .unwrap_or(name)
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+fn component_state(data: &ComponentData) -> Option<String> {
+    use component_data::Data;
+
+    let state = match &data.data {
+        Some(Data::Meter(meter)) => meter.state.as_ref()?.component_state().as_str_name(),
+        Some(Data::Inverter(inverter)) => inverter.state.as_ref()?.component_state().as_str_name(),
+        Some(Data::Battery(battery)) => battery.state.as_ref()?.component_state().as_str_name(),
+        Some(Data::EvCharger(ev_charger)) => {
+            ev_charger.state.as_ref()?.component_state().as_str_name()
+        }
+        _ => return None,
+    };
+    Some(strip_prefix(state, "COMPONENT_STATE_"))
+}
+
+impl View {
+    async fn collect(
+        config: &Config,
+        timeout_tracker: &TimeoutTracker,
+    ) -> Result<Self, tonic::Status> {
+        let pending = timeout_tracker.pending(
+            config.simulation_time().await?,
+            config.retain_requests_duration().await?,
+        );
+        let registry = config.registry();
+
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut has_parent = HashSet::new();
+        for conn in &registry.connections().connections {
+            children.entry(conn.start).or_default().push(conn.end);
+            has_parent.insert(conn.end);
+        }
+        let last_commands = config.metrics().last_commands();
+
+        let mut nodes = Vec::new();
+        let mut stack: Vec<(usize, u64)> = registry
+            .components()
+            .components
+            .iter()
+            .filter(|c| !has_parent.contains(&c.id))
+            .map(|c| (0, c.id))
+            .rev()
+            .collect();
+        while let Some((depth, id)) = stack.pop() {
+            let category = registry
+                .category(id)
+                .unwrap_or(ComponentCategory::Unspecified);
+            let data = if registry.stream_interval(id).is_some() {
+                config.get_component_data(id).await.ok()
+            } else {
+                None
+            };
+            nodes.push(Node {
+                depth,
+                id,
+                category: strip_prefix(category.as_str_name(), "COMPONENT_CATEGORY_"),
+                values: data
+                    .as_ref()
+                    .map(|(data, _, _)| component_values(data).1)
+                    .unwrap_or_default(),
+                state: data.as_ref().and_then(|(data, _, _)| component_state(data)),
+                recent_command: last_commands
+                    .get(&id)
+                    .is_some_and(|t| t.elapsed() < RECENT_COMMAND),
+            });
+            for child in children.get(&id).into_iter().flatten().rev() {
+                stack.push((depth + 1, *child));
+            }
+        }
+
+        Ok(Self { nodes, pending })
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [tree_area, side_area] =
+            Layout::horizontal([Constraint::Min(60), Constraint::Length(32)]).areas(frame.area());
+
+        let items: Vec<ListItem> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let mut spans = vec![
+                    Span::raw("  ".repeat(node.depth)),
+                    Span::styled(
+                        format!("{} {}", node.category, node.id),
+                        Style::default().add_modifier(Modifier::BOLD),
+                    ),
+                ];
+                if let Some(state) = &node.state {
+                    spans.push(Span::styled(
+                        format!(" [{state}]"),
+                        Style::default().fg(Color::Cyan),
+                    ));
+                }
+                for (name, value) in &node.values {
+                    spans.push(Span::raw(format!("  {name}: {value:.1}")));
+                }
+                let style = if node.recent_command {
+                    Style::default().fg(Color::Yellow)
+                } else {
+                    Style::default()
+                };
+                ListItem::new(Line::from(spans)).style(style)
+            })
+            .collect();
+        frame.render_widget(
+            List::new(items).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" Components (q to quit) "),
+            ),
+            tree_area,
+        );
+
+        let pending: Vec<ListItem> = self
+            .pending
+            .iter()
+            .map(|(id, left)| ListItem::new(format!("{id}: expires in {:.1}s", left.as_secs_f64())))
+            .collect();
+        frame.render_widget(
+            List::new(pending).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" Request timeouts "),
+            ),
+            side_area,
+        );
+    }
+}
+
+fn quit_requested() -> std::io::Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()? {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if ctrl_c || key.code == KeyCode::Char('q') || key.code == KeyCode::Esc {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Shows a live dashboard of the component tree in the terminal, until
+/// the user quits.  The terminal is restored before returning.
+pub(crate) async fn run(config: Config, timeout_tracker: TimeoutTracker) {
+    let mut terminal = ratatui::init();
+    let mut last_draw = Instant::now() - Duration::from_secs(1);
+    loop {
+        if last_draw.elapsed() >= Duration::from_millis(500) {
+            let view = match View::collect(&config, &timeout_tracker).await {
+                Ok(view) => view,
+                Err(status) => {
+                    log::error!("Unable to read the simulation: {}", status.message());
+                    break;
+                }
+            };
+            if let Err(err) = terminal.draw(|frame| view.render(frame)) {
+                log::error!("Unable to draw dashboard: {}", err);
+                break;
+            }
+            last_draw = Instant::now();
+        }
+        match quit_requested() {
+            Ok(false) => {}
+            Ok(true) => break,
+            Err(err) => {
+                log::error!("Unable to read terminal events: {}", err);
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    ratatui::restore();
+}
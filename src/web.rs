@@ -0,0 +1,182 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures::Stream;
+use serde_json::{json, Value};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{
+    config::Config,
+    metrics::component_values,
+    proto::{common::components::ComponentCategory, microgrid::ComponentData},
+};
+
+const INDEX_HTML: &str = include_str!("../web/index.html");
+
+fn has_stream(category: ComponentCategory) -> bool {
+    matches!(
+        category,
+        ComponentCategory::Meter
+            | ComponentCategory::Inverter
+            | ComponentCategory::Battery
+            | ComponentCategory::EvCharger
+    )
+}
+
+fn category_name(category: ComponentCategory) -> String {
+    category
+        .as_str_name()
+        .trim_start_matches("COMPONENT_CATEGORY_")
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+async fn handle_index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+async fn handle_topology(State(config): State<Config>) -> Json<Value> {
+    let registry = config.registry();
+    let components: Vec<Value> = registry
+        .components()
+        .components
+        .iter()
+        .map(|c| {
+            json!({
+                "id": c.id,
+                "name": c.name,
+                "category": category_name(c.category()),
+                "streams": has_stream(c.category()),
+            })
+        })
+        .collect();
+    let connections: Vec<Value> = registry
+        .connections()
+        .connections
+        .iter()
+        .map(|c| json!({ "start": c.start, "end": c.end }))
+        .collect();
+    Json(json!({ "components": components, "connections": connections }))
+}
+
+/// The `sample` event of a component's data.
+fn sample_json(data: &ComponentData) -> Value {
+    let ts = data
+        .ts
+        .as_ref()
+        .map(|ts| ts.seconds * 1000 + ts.nanos as i64 / 1_000_000);
+    let (category, values) = component_values(data);
+    let values: serde_json::Map<String, Value> = values
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.into()))
+        .collect();
+    json!({
+        "id": data.id,
+        "ts": ts,
+        "category": category,
+        "values": values,
+    })
+}
+
+/// Streams the data of all components, at their stream intervals, as
+/// one `sample` event per sample.  The components are sampled directly,
+/// without the comm faults of `stream_component_data` clients.
+async fn handle_events(
+    State(config): State<Config>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Value>(128);
+
+    let ids: Vec<u64> = config
+        .components()
+        .components
+        .iter()
+        .filter(|c| has_stream(c.category()))
+        .map(|c| c.id)
+        .collect();
+    for id in ids {
+        let config = config.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut next = tokio::time::Instant::now();
+            // The component can be gone after a reload.
+            while let Ok((data, interval, _)) = config.get_component_data(id).await {
+                if tx.send(sample_json(&data)).await.is_err() {
+                    break;
+                }
+                // Skip samples that are already late, instead of
+                // catching up with them.
+                next = (next + Duration::from_millis(interval)).max(tokio::time::Instant::now());
+                tokio::time::sleep_until(next).await;
+            }
+        });
+    }
+
+    let stream = ReceiverStream::new(rx)
+        .map(|sample| Ok(Event::default().event("sample").data(sample.to_string())));
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+/// Serves a web page with the component graph and live charts of the
+/// component data.
+pub(crate) async fn serve(config: Config, addr: String) {
+    let app = Router::new()
+        .route("/", get(handle_index))
+        .route("/topology", get(handle_topology))
+        .route("/events", get(handle_events))
+        .with_state(config);
+
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Unable to serve the web UI on {}: {}", addr, err);
+            return;
+        }
+    };
+    log::info!("Serving the web UI on http://{}/", addr);
+    if let Err(err) = axum::serve(listener, app).await {
+        log::error!("Web UI server failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_the_topology() {
+        let config = Config::from_source(
+            "(make-grid :id 1 :successors (list (make-meter :id 2 :hidden t) (make-meter :id 3)))",
+        )
+        .await
+        .unwrap();
+
+        let Json(topology) = handle_topology(State(config.clone())).await;
+        let mut components = topology["components"].as_array().unwrap().clone();
+        components.sort_by_key(|c| c["id"].as_u64());
+        // The hidden meter isn't shown.
+        assert_eq!(
+            components,
+            [
+                json!({ "id": 1, "name": "grid", "category": "grid", "streams": false }),
+                json!({ "id": 3, "name": "meter-3", "category": "meter", "streams": true }),
+            ]
+        );
+        assert_eq!(topology["connections"], json!([{ "start": 1, "end": 3 }]));
+
+        let (data, _, _) = config.get_component_data(3).await.unwrap();
+        let sample = sample_json(&data);
+        assert_eq!(sample["id"], 3);
+        assert_eq!(sample["category"], "meter");
+        assert!(sample["values"]["power"].is_number());
+
+        config.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use microsim::{
+    proto::microgrid::{
+        component_data::Data, microgrid_client::MicrogridClient, ComponentFilter, ComponentIdParam,
+        SetPowerActiveParam,
+    },
+    SimHandle, SimHarness,
+};
+use tonic::{transport::Channel, Code};
+
+const SITE: &str = r#"
+(make-grid
+ :id 1
+ :successors (list
+              (make-meter
+               :id 2
+               :successors (list
+                            (make-battery-inverter
+                             :id 3
+                             :successors (list (make-battery :id 4)))))))
+"#;
+
+/// A battery inverter that provides 10 kW of FCR, during an
+/// underfrequency of 49.9 Hz, which activates half of it.
+const FCR_SITE: &str = r#"
+(grid-event :frequency 49.9)
+(make-grid
+ :id 1
+ :successors (list
+              (make-meter
+               :id 2
+               :successors (list
+                            (make-battery-inverter
+                             :id 3
+                             :config '((fcr-power . 10000.0))
+                             :successors (list
+                                          (make-battery
+                                           :id 4
+                                           :config '((initial-soc . 50.0)))))))))
+"#;
+
+/// A site that overloads its 50 A grid fuse by about 3 times, once
+/// `site-load` is raised to 100 kW.  The fuse then trips after 20
+/// seconds.
+const FUSE_SITE: &str = r#"
+(setq grid-fuse-trip-curve '((2.0 . 20.0)))
+(setq site-load 0.0)
+(make-grid
+ :id 1
+ :rated-fuse-current 50
+ :successors (list (make-meter :id 2 :power 'site-load)))
+"#;
+
+async fn spawn_site(site: &str) -> (SimHandle, MicrogridClient<Channel>) {
+    let sim = SimHarness::from_source(site)
+        .manual_time()
+        .spawn()
+        .await
+        .unwrap();
+    let client = MicrogridClient::connect(sim.endpoint()).await.unwrap();
+    (sim, client)
+}
+
+async fn spawn() -> (SimHandle, MicrogridClient<Channel>) {
+    spawn_site(SITE).await
+}
+
+/// Reads the active power of an inverter from its data stream.
+async fn streamed_inverter_power(client: &mut MicrogridClient<Channel>, id: u64) -> f32 {
+    let mut stream = client
+        .stream_component_data(ComponentIdParam { id })
+        .await
+        .unwrap()
+        .into_inner();
+    let data = stream.message().await.unwrap().unwrap();
+    let Some(Data::Inverter(inverter)) = data.data else {
+        panic!("No inverter data for component {id}: {data:?}");
+    };
+    let ac = inverter.data.and_then(|data| data.ac).unwrap();
+    ac.power_active.unwrap().value
+}
+
+async fn power(sim: &SimHandle, id: u64) -> f64 {
+    sim.eval(&format!("(component-value {id} 'power)"))
+        .await
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn only_the_grid_can_be_started_and_stopped() {
+    let (sim, mut client) = spawn().await;
+
+    for id in [2, 3, 4] {
+        let status = client.stop(ComponentIdParam { id }).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented, "stop {id}");
+        let status = client.start(ComponentIdParam { id }).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented, "start {id}");
+    }
+    let status = client.stop(ComponentIdParam { id: 99 }).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+
+    client.stop(ComponentIdParam { id: 1 }).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "outage");
+    assert_eq!(
+        sim.eval("(component-state-override 3)").await.unwrap(),
+        "standby"
+    );
+    client.start(ComponentIdParam { id: 1 }).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "connected");
+    assert_eq!(
+        sim.eval("(component-state-override 3)").await.unwrap(),
+        "nil"
+    );
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn fcr_droop_adds_to_the_inverter_power() {
+    let (sim, mut client) = spawn_site(FCR_SITE).await;
+
+    client
+        .set_power_active(SetPowerActiveParam {
+            component_id: 3,
+            power: 1000.0,
+        })
+        .await
+        .unwrap();
+    sim.step(Duration::from_secs(1)).await.unwrap();
+
+    // The batteries keep the requested power, and the droop discharges
+    // on top of it.
+    assert_eq!(power(&sim, 4).await, 1000.0);
+    assert!((power(&sim, 3).await + 4000.0).abs() < 1e-6);
+    assert!((power(&sim, 2).await + 4000.0).abs() < 1e-6);
+
+    // Without the grid, there is no droop.
+    client.stop(ComponentIdParam { id: 1 }).await.unwrap();
+    assert_eq!(power(&sim, 3).await, power(&sim, 4).await);
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn grid_fuse_events_have_simulated_times() {
+    let (sim, _client) = spawn_site(FUSE_SITE).await;
+
+    sim.step(Duration::from_secs(10)).await.unwrap();
+    sim.set("site-load", "100000.0").await.unwrap();
+    sim.step(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "connected");
+    sim.step(Duration::from_secs(20)).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "tripped");
+
+    // Newest first: the overload after 11 seconds, and the trip 20
+    // seconds later.
+    assert_eq!(
+        sim.eval(
+            "(equal (seq-map (lambda (event) (alist-get 'event event)) grid-fuse-events)
+                    '(trip overload))"
+        )
+        .await
+        .unwrap(),
+        "t"
+    );
+    assert_eq!(
+        sim.eval(
+            "(equal (seq-map (lambda (event) (alist-get 'time event)) grid-fuse-events)
+                    '(31.0 11.0))"
+        )
+        .await
+        .unwrap(),
+        "t"
+    );
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn scenarios_run_on_simulated_time() {
+    let (sim, _client) = spawn().await;
+    let filename = std::env::temp_dir().join("microsim-stepped-scenario.lisp");
+    std::fs::write(
+        &filename,
+        r#"
+(scenario "Stepped outage"
+  (at 10
+      (grid-outage)
+      (expect-within 1 (not (grid-connected-p)) "grid is disconnected"))
+  (at (minutes 1) (restore-grid)))
+"#,
+    )
+    .unwrap();
+    sim.load_scenario(filename.to_str().unwrap()).await.unwrap();
+
+    sim.step(Duration::from_secs(5)).await.unwrap();
+    assert_eq!(sim.get("scenario-status").await.unwrap(), "running");
+    sim.step(Duration::from_secs(5)).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "outage");
+    sim.step(Duration::from_secs(50)).await.unwrap();
+    assert_eq!(sim.get("grid-state").await.unwrap(), "connected");
+    assert_eq!(sim.get("scenario-status").await.unwrap(), "passed");
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn power_requests_apply_and_expire_on_simulated_time() {
+    let (sim, mut client) = spawn().await;
+
+    let components = client
+        .list_components(ComponentFilter::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let mut ids: Vec<_> = components.components.iter().map(|c| c.id).collect();
+    ids.sort();
+    assert_eq!(ids, [1, 2, 3, 4]);
+
+    client
+        .set_power_active(SetPowerActiveParam {
+            component_id: 3,
+            power: -5000.0,
+        })
+        .await
+        .unwrap();
+    sim.step(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(streamed_inverter_power(&mut client, 3).await, -5000.0);
+
+    // The request expires after `retain-requests-duration-ms` of
+    // simulated time, and falls back to zero.
+    sim.step(Duration::from_secs(58)).await.unwrap();
+    assert_eq!(power(&sim, 3).await, -5000.0);
+    sim.step(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(power(&sim, 3).await, 0.0);
+    assert_eq!(streamed_inverter_power(&mut client, 3).await, 0.0);
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn measurements_are_read_from_the_component_config() {
+    let (sim, mut client) = spawn_site(
+        r#"
+(make-grid
+ :id 1
+ :successors (list
+              (make-battery-inverter
+               :id 3
+               :config '((measurement . ((power . ((offset . 100.0))))))
+               :successors (list (make-battery :id 4)))))
+"#,
+    )
+    .await;
+
+    client
+        .set_power_active(SetPowerActiveParam {
+            component_id: 3,
+            power: -5000.0,
+        })
+        .await
+        .unwrap();
+    sim.step(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(power(&sim, 3).await, -5000.0);
+    assert_eq!(streamed_inverter_power(&mut client, 3).await, -4900.0);
+
+    sim.shutdown().await;
+}
+
+#[tokio::test]
+async fn components_added_at_runtime_are_listed() {
+    let (sim, mut client) = spawn().await;
+
+    // Evaluating code that doesn't change the components leaves the
+    // registry as it is.
+    assert_eq!(sim.get("registry-changed").await.unwrap(), "nil");
+    sim.set("consumer-power-base", "20000.0").await.unwrap();
+    assert_eq!(sim.get("registry-changed").await.unwrap(), "nil");
+
+    sim.eval("(make-meter :id 5 :power 10.0)").await.unwrap();
+    assert_eq!(sim.get("registry-changed").await.unwrap(), "nil");
+    let components = client
+        .list_components(ComponentFilter::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let mut ids: Vec<_> = components.components.iter().map(|c| c.id).collect();
+    ids.sort();
+    assert_eq!(ids, [1, 2, 3, 4, 5]);
+
+    sim.shutdown().await;
+}